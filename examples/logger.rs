@@ -0,0 +1,44 @@
+//! Log stats from a Prostar MPPT as JSON lines on stdout.
+//!
+//! cargo run --example logger -- /dev/ttyUSB0 1 10
+//!
+//! The arguments are the serial device, the modbus id and the poll
+//! interval in seconds, defaulting to 1 and 10. The port is reopened
+//! if it fails, and after it opens, polling waits for the controller
+//! to settle. Any active faults or alarms are logged on their own
+//! line along with the recommended action.
+use anyhow::{Context, Result};
+use morningstar::prostar_mppt::{
+    self as ps,
+    conditions::{conditions, Severity},
+};
+use std::{env, time::Duration};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+    let device = args.get(1).map(|s| s.as_str()).unwrap_or("/dev/ttyUSB0");
+    let modbus_id = args.get(2).map(|s| s.parse()).transpose()?.unwrap_or(1);
+    let interval = args.get(3).map(|s| s.parse()).transpose()?.unwrap_or(10);
+    let con = ps::ConnectionBuilder::new(device, modbus_id)
+        .request_timeout(Duration::from_secs(5))
+        .reconnect(ps::Reconnect::default())
+        .warm_up(ps::WarmUp::default())
+        .connect()
+        .await
+        .context("failed to connect")?;
+    con.probe_capabilities().await?;
+    loop {
+        match con.stats().await {
+            Err(e) => eprintln!("failed to read stats: {:#}", e),
+            Ok(stats) => {
+                println!("{}", serde_json::to_string(&stats)?);
+                for c in conditions(&stats).filter(|c| c.severity() >= Severity::Warning)
+                {
+                    eprintln!("{:?} {}: {}", c.severity(), c, c.action());
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
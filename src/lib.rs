@@ -1,4 +1,4 @@
-/// There is a sub module for each hardware family, as well as a common error type.
+//! There is a sub module for each hardware family, as well as a common error type.
 
 #[macro_use]
 extern crate bitflags;
@@ -9,21 +9,86 @@ extern crate anyhow;
 
 /**
 Interface with the Prostar MPPT (all models) as documented at
-http://support.morningstarcorp.com/wp-content/uploads/2015/12/PSMPPT_public-MODBUS-doc_v04.pdf
+<http://support.morningstarcorp.com/wp-content/uploads/2015/12/PSMPPT_public-MODBUS-doc_v04.pdf>
 
 # Examples
-```
+```no_run
 use morningstar::prostar_mppt as ps;
-use std::{thread::sleep, time::{Instant, Duration}};
+# async fn run() {
 
 let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
-println!("{}", con.stats().expect("failed to get stats"));
+println!("{}", con.stats().await.expect("failed to get stats"));
 
 // Stop charging the battery
 con.write_coil(ps::Coil::ChargeDisconnect, true).await.expect("failed to stop charging");
 
 // Start Charging again
 con.write_coil(ps::Coil::ChargeDisconnect, false).await.expect("failed to start charging");
+# }
 ```
 */
 pub mod prostar_mppt;
+pub mod units;
+
+use anyhow::Result;
+use chrono::prelude::*;
+use futures::future::BoxFuture;
+use uom::si::f32::{ElectricCurrent, ElectricPotential, ThermodynamicTemperature};
+
+/// Where a controller is in its charge cycle, in terms every family
+/// shares
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ChargeStage {
+    /// not charging, at night, disconnected, or starting up
+    Off,
+    Bulk,
+    Absorption,
+    Float,
+    Equalize,
+    Fault,
+    /// a family specific state with no common equivalent
+    Other,
+}
+
+/** The readings every charge controller family provides */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CoreStats {
+    pub timestamp: DateTime<Local>,
+    pub battery_voltage: ElectricPotential,
+    pub array_voltage: ElectricPotential,
+    pub charge_current: ElectricCurrent,
+    pub load_current: ElectricCurrent,
+    pub heatsink_temperature: ThermodynamicTemperature,
+    pub charge_stage: ChargeStage,
+    /// any fault is active
+    pub fault: bool,
+    /// any alarm is active
+    pub alarm: bool,
+}
+
+/** The settings every charge controller family provides */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CoreSettings {
+    pub regulation_voltage: ElectricPotential,
+    pub float_voltage: ElectricPotential,
+    pub load_low_voltage_disconnect: ElectricPotential,
+    pub load_low_voltage_reconnect: ElectricPotential,
+}
+
+/// Operations common to every family's `Connection`, so monitoring
+/// software can handle a mixed fleet as `Box<dyn ChargeController>`.
+/// Anything family specific is still on the family's `Connection`.
+pub trait ChargeController: Send + Sync {
+    /// The name of the family module, e.g. "prostar_mppt"
+    fn family(&self) -> &'static str;
+
+    fn core_stats(&self) -> BoxFuture<'_, Result<CoreStats>>;
+
+    fn core_settings(&self) -> BoxFuture<'_, Result<CoreSettings>>;
+
+    /// Stop (false) or resume (true) charging the battery
+    fn set_charging(&self, enabled: bool) -> BoxFuture<'_, Result<()>>;
+
+    /// Disconnect (false) or reconnect (true) the load
+    fn set_load(&self, enabled: bool) -> BoxFuture<'_, Result<()>>;
+}
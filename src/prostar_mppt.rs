@@ -1,12 +1,11 @@
-use anyhow::{Context, Result};
 /**
 Interface with the Prostar MPPT (all models) as documented at
 http://support.morningstarcorp.com/wp-content/uploads/2015/12/PSMPPT_public-MODBUS-doc_v04.pdf
 
 # Examples
-```
+```no_run
 use morningstar::prostar_mppt as ps;
-use std::{thread::sleep, time::{Instant, Duration}};
+# async fn run() {
 
 let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
 println!("{}", con.stats().await.expect("failed to get stats"));
@@ -16,13 +15,48 @@ con.write_coil(ps::Coil::ChargeDisconnect, true).await.expect("failed to stop ch
 
 // Start Charging again
 con.write_coil(ps::Coil::ChargeDisconnect, false).await.expect("failed to start charging");
+# }
 ```
 */
+use crate::{ChargeController, ChargeStage, CoreSettings, CoreStats};
+use anyhow::{Context, Result};
+use ascii::Ascii;
+use calibration::Calibration;
 use chrono::prelude::*;
+use fence::{Fence, Fences};
+use futures::future::BoxFuture;
 use half::f16;
-use std::{fmt, mem::transmute, thread::sleep, time::Duration};
+use overlay::{FieldValue, Overlay};
+use rts::RtsDriven;
+use std::{
+    collections::HashMap,
+    error, fmt,
+    fs::File,
+    future::{self, Future},
+    io::{self, BufReader},
+    mem,
+    ops::{Deref, DerefMut, Range},
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Mutex, MutexGuard},
+    task::{self, JoinHandle},
+    time::{sleep_until, Sleep},
+};
 use tokio_modbus::{client::Context as Modbus, prelude::*};
-use tokio_serial::{self, DataBits, FlowControl, Parity, SerialStream, StopBits};
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+use tokio_serial::{self, FlowControl, SerialPortType, SerialStream};
+pub use tokio_serial::{DataBits, Parity, StopBits};
 use uom::si::{
     electric_charge::ampere_hour,
     electric_current::ampere,
@@ -36,6 +70,27 @@ use uom::si::{
     Unit,
 };
 
+pub mod anomaly;
+pub mod archive;
+mod ascii;
+pub mod calibration;
+pub mod codec;
+#[cfg(any(feature = "cbor", feature = "msgpack"))]
+pub mod compact;
+pub mod compensation;
+pub mod conditions;
+pub mod conformance;
+pub mod efficiency;
+pub mod fence;
+pub mod ids;
+pub mod interlock;
+pub mod overlay;
+pub mod preset;
+pub mod provision;
+pub mod registry;
+mod rts;
+pub mod trend;
+
 fn gu32(h: u16, l: u16) -> u32 {
     (h as u32) << 16 | (l as u32)
 }
@@ -63,15 +118,13 @@ fn ah(u: f32) -> ElectricCharge {
     ElectricCharge::new::<ampere_hour>(u)
 }
 fn to_ic(c: ThermodynamicTemperature) -> u16 {
-    unsafe { transmute::<i16, u16>(c.get::<degree_celsius>() as i16) }
+    c.get::<degree_celsius>() as i16 as u16
 }
 fn c(u: f32) -> ThermodynamicTemperature {
     ThermodynamicTemperature::new::<degree_celsius>(u)
 }
 fn ic(u: u16) -> ThermodynamicTemperature {
-    ThermodynamicTemperature::new::<degree_celsius>(
-        unsafe { transmute::<u16, i16>(u) } as f32
-    )
+    ThermodynamicTemperature::new::<degree_celsius>(u as i16 as f32)
 }
 fn w(u: f32) -> Power {
     Power::new::<watt>(u)
@@ -107,8 +160,18 @@ fn to_mn(m: Time) -> u16 {
     m.get::<minute>() as u16
 }
 
+const STATS_LEN: usize = 0x0051;
 const SETTINGS_BASE: usize = 0xE000;
 const SETTINGS_END: usize = 0xE038;
+// Connection::slave when the modbus id is unknown
+const NO_SLAVE: u16 = 0x100;
+// how long to let slaves act on a broadcast before the next request
+const BROADCAST_TURNAROUND: Duration = Duration::from_millis(200);
+/// The default pause before each register written by `write_settings`
+const WRITE_DELAY: Duration = Duration::from_millis(100);
+
+// identifies each Connection to the write fences
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ChargeState {
@@ -151,6 +214,25 @@ impl From<u16> for ChargeState {
     }
 }
 
+impl From<ChargeState> for u16 {
+    fn from(s: ChargeState) -> u16 {
+        match s {
+            ChargeState::Start => 0,
+            ChargeState::NightCheck => 1,
+            ChargeState::Disconnect => 2,
+            ChargeState::Night => 3,
+            ChargeState::Fault => 4,
+            ChargeState::BulkMPPT => 5,
+            ChargeState::Absorption => 6,
+            ChargeState::Float => 7,
+            ChargeState::Equalize => 8,
+            ChargeState::Slave => 9,
+            ChargeState::Fixed => 10,
+            ChargeState::UnknownState(i) => i,
+        }
+    }
+}
+
 bitflags! {
     #[derive(Default, Serialize, Deserialize)]
     pub struct ArrayFaults: u16 {
@@ -257,8 +339,47 @@ impl From<u16> for LoadState {
     }
 }
 
+impl From<LoadState> for u16 {
+    fn from(s: LoadState) -> u16 {
+        match s {
+            LoadState::Start => 0,
+            LoadState::Normal => 1,
+            LoadState::LVDWarning => 2,
+            LoadState::LVD => 3,
+            LoadState::Fault => 4,
+            LoadState::Disconnect => 5,
+            LoadState::NormalOff => 6,
+            LoadState::Override => 7,
+            LoadState::NotUsed => 8,
+            LoadState::Unknown(i) => i,
+        }
+    }
+}
+
+bitflags! {
+    /// Register groups at the end of the stats and settings blocks
+    /// that are probed individually by
+    /// `Connection::probe_capabilities`. Fields backed by a group the
+    /// controller doesn't answer read as zero and are never written.
+    #[derive(Serialize, Deserialize)]
+    pub struct Capabilities: u32 {
+        /// array_voltage_fixed and array_voc_percent_fixed (0x004F-0x0050)
+        const ARRAY_FIXED_STATS    = 0x0001;
+        /// mppt_fixed_vmp and mppt_fixed_vmp_percent (0xE036-0xE037)
+        const MPPT_FIXED_SETTINGS  = 0x0002;
+        /// charge_current_limit (0xE038)
+        const CHARGE_CURRENT_LIMIT = 0x0004;
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::all()
+    }
+}
+
 /** Charge controller statistics */
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stats {
     pub timestamp: DateTime<Local>,
     pub software_version: u16,
@@ -312,6 +433,13 @@ pub struct Stats {
     pub array_voltage_max_daily: ElectricPotential,
     pub array_voltage_fixed: ElectricPotential,
     pub array_voc_percent_fixed: f32,
+    /// true if current offsets from a `calibration::Calibration` have
+    /// been applied
+    #[serde(default)]
+    pub calibrated: bool,
+    /// fields from the connection's `overlay::Overlay`, by name
+    #[serde(default)]
+    pub extras: HashMap<String, FieldValue>,
 }
 
 impl Default for Stats {
@@ -369,15 +497,106 @@ impl Default for Stats {
             array_voltage_max_daily: ElectricPotential::default(),
             array_voltage_fixed: ElectricPotential::default(),
             array_voc_percent_fixed: 0.,
+            calibrated: false,
+            extras: HashMap::new(),
+        }
+    }
+}
+
+impl Stats {
+    /// Decode the stats block starting at register 0. Registers past
+    /// the end of `raw` read as zero.
+    fn decode(raw: &[u16]) -> Stats {
+        let r = |i: usize| raw.get(i).copied().unwrap_or(0);
+        Stats {
+            timestamp: Local::now(),
+            software_version: r(0x0000),
+            battery_voltage_settings_multiplier: r(0x0001),
+            supply_3v3: v(gf32(r(0x0004))),
+            supply_12v: v(gf32(r(0x0005))),
+            supply_5v: v(gf32(r(0x0006))),
+            gate_drive_voltage: v(gf32(r(0x0007))),
+            battery_terminal_voltage: v(gf32(r(0x0012))),
+            array_voltage: v(gf32(r(0x0013))),
+            load_voltage: v(gf32(r(0x0014))),
+            charge_current: a(gf32(r(0x0010))),
+            array_current: a(gf32(r(0x0011))),
+            load_current: a(gf32(r(0x0016))),
+            battery_current_net: a(gf32(r(0x0015))),
+            battery_sense_voltage: v(gf32(r(0x0017))),
+            meterbus_voltage: v(gf32(r(0x0008))),
+            heatsink_temperature: c(gf32(r(0x001A))),
+            battery_temperature: c(gf32(r(0x001B))),
+            ambient_temperature: c(gf32(r(0x001C))),
+            rts_temperature: {
+                let t = gf32(r(0x001D));
+                if t.is_nan() {
+                    None
+                } else {
+                    Some(c(t))
+                }
+            },
+            u_inductor_temperature: c(gf32(r(0x001E))),
+            v_inductor_temperature: c(gf32(r(0x001F))),
+            w_inductor_temperature: c(gf32(r(0x0020))),
+            charge_state: ChargeState::from(r(0x0021)),
+            array_faults: ArrayFaults::from_bits_truncate(r(0x0022)),
+            battery_voltage_slow: v(gf32(r(0x0023))),
+            target_voltage: v(gf32(r(0x0024))),
+            ah_charge_resettable: ah(gu32(r(0x0026), r(0x0027)) as f32 * 0.1),
+            ah_charge_total: ah(gu32(r(0x0028), r(0x0029)) as f32 * 0.1),
+            kwh_charge_resettable: kwh(gf32(r(0x002A))),
+            kwh_charge_total: kwh(gf32(r(0x002B))),
+            load_state: LoadState::from(r(0x002E)),
+            load_faults: LoadFaults::from_bits_truncate(r(0x002F)),
+            lvd_setpoint: v(gf32(r(0x0030))),
+            ah_load_resettable: ah(gu32(r(0x0032), r(0x0033)) as f32 * 0.1),
+            ah_load_total: ah(gu32(r(0x0034), r(0x0035)) as f32 * 0.1),
+            hourmeter: hr(gu32(r(0x0036), r(0x0037)) as f32),
+            alarms: Alarms::from_bits_truncate(
+                (r(0x0038) as u32) << 16 | r(0x0039) as u32,
+            ),
+            array_power: w(gf32(r(0x003C))),
+            array_vmp: v(gf32(r(0x003D))),
+            array_max_power_sweep: w(gf32(r(0x003E))),
+            array_voc: v(gf32(r(0x003F))),
+            battery_v_min_daily: v(gf32(r(0x0041))),
+            battery_v_max_daily: v(gf32(r(0x0042))),
+            ah_charge_daily: ah(gf32(r(0x0043))),
+            ah_load_daily: ah(gf32(r(0x0044))),
+            array_faults_daily: ArrayFaults::from_bits_truncate(r(0x0045)),
+            load_faults_daily: LoadFaults::from_bits_truncate(r(0x0046)),
+            alarms_daily: Alarms::from_bits_truncate(
+                (r(0x0047) as u32) << 16 | r(0x0048) as u32,
+            ),
+            array_voltage_max_daily: v(gf32(r(0x004C))),
+            array_voltage_fixed: v(gf32(r(0x004F))),
+            array_voc_percent_fixed: gf32(r(0x0050)),
+            calibrated: false,
+            extras: HashMap::new(),
         }
     }
+
+    /// True if the heatsink or inductor temperature limit alarm is
+    /// active. The load side reports its own thermal shutdown as
+    /// `LoadFaults::HIGH_TEMP_DISCONNECT`.
+    pub fn thermal_limit(&self) -> bool {
+        self.alarms.intersects(Alarms::HEATSINK_TEMP_LIMIT | Alarms::INDUCTOR_TEMP_LIMIT)
+    }
+
+    /// True if the controller is folding back charge current because
+    /// it is too hot, a temperature limit alarm together with current
+    /// limiting. Low charge current without this flag is just weak sun.
+    pub fn derating(&self) -> bool {
+        self.thermal_limit() && self.alarms.contains(Alarms::CURRENT_LIMIT)
+    }
 }
 
 macro_rules! as_unit {
     ($f:ident, $obj:ident, $field:ident, $unit:ident) => {
-        write!(
+        writeln!(
             $f,
-            "    {}: {:.2} {},\n",
+            "    {}: {:.2} {},",
             stringify!($field),
             $obj.$field.get::<$unit>(),
             $unit::abbreviation()
@@ -387,12 +606,12 @@ macro_rules! as_unit {
 
 impl fmt::Display for Stats {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Stats {{\n")?;
-        write!(f, "    timestamp: {},\n", self.timestamp)?;
-        write!(f, "    software_version: {},\n", self.software_version)?;
-        write!(
+        writeln!(f, "Stats {{")?;
+        writeln!(f, "    timestamp: {},", self.timestamp)?;
+        writeln!(f, "    software_version: {},", self.software_version)?;
+        writeln!(
             f,
-            "    battery_voltage_settings_multiplier: {},\n",
+            "    battery_voltage_settings_multiplier: {},",
             self.battery_voltage_settings_multiplier
         )?;
         as_unit!(f, self, supply_3v3, volt)?;
@@ -412,10 +631,10 @@ impl fmt::Display for Stats {
         as_unit!(f, self, battery_temperature, degree_celsius)?;
         as_unit!(f, self, ambient_temperature, degree_celsius)?;
         match self.rts_temperature {
-            None => write!(f, "    rts_temperature: None,\n")?,
-            Some(t) => write!(
+            None => writeln!(f, "    rts_temperature: None,")?,
+            Some(t) => writeln!(
                 f,
-                "    rts_temperature: {} {:.2},\n",
+                "    rts_temperature: {} {:.2},",
                 t.get::<degree_celsius>(),
                 degree_celsius::abbreviation()
             )?,
@@ -423,21 +642,22 @@ impl fmt::Display for Stats {
         as_unit!(f, self, u_inductor_temperature, degree_celsius)?;
         as_unit!(f, self, v_inductor_temperature, degree_celsius)?;
         as_unit!(f, self, w_inductor_temperature, degree_celsius)?;
-        write!(f, "    charge_state: {:#?},\n", self.charge_state)?;
-        write!(f, "    array_faults: {:#?},\n", self.array_faults)?;
+        writeln!(f, "    charge_state: {:#?},", self.charge_state)?;
+        writeln!(f, "    array_faults: {:#?},", self.array_faults)?;
         as_unit!(f, self, battery_voltage_slow, volt)?;
         as_unit!(f, self, target_voltage, volt)?;
         as_unit!(f, self, ah_charge_resettable, ampere_hour)?;
         as_unit!(f, self, ah_charge_total, ampere_hour)?;
         as_unit!(f, self, kwh_charge_resettable, kilowatt_hour)?;
         as_unit!(f, self, kwh_charge_total, kilowatt_hour)?;
-        write!(f, "    load_state: {:#?},\n", self.load_state)?;
-        write!(f, "    load_faults: {:#?},\n", self.load_faults)?;
+        writeln!(f, "    load_state: {:#?},", self.load_state)?;
+        writeln!(f, "    load_faults: {:#?},", self.load_faults)?;
         as_unit!(f, self, lvd_setpoint, volt)?;
         as_unit!(f, self, ah_load_resettable, ampere_hour)?;
         as_unit!(f, self, ah_load_total, ampere_hour)?;
         as_unit!(f, self, hourmeter, hour)?;
-        write!(f, "    alarms: {:#?},\n", self.alarms)?;
+        writeln!(f, "    alarms: {:#?},", self.alarms)?;
+        writeln!(f, "    derating: {},", self.derating())?;
         as_unit!(f, self, array_power, watt)?;
         as_unit!(f, self, array_vmp, volt)?;
         as_unit!(f, self, array_max_power_sweep, watt)?;
@@ -446,17 +666,62 @@ impl fmt::Display for Stats {
         as_unit!(f, self, battery_v_max_daily, volt)?;
         as_unit!(f, self, ah_charge_daily, ampere_hour)?;
         as_unit!(f, self, ah_load_daily, ampere_hour)?;
-        write!(f, "    array_faults_daily: {:#?},\n", self.array_faults_daily)?;
-        write!(f, "    load_faults_daily: {:#?},\n", self.load_faults_daily)?;
-        write!(f, "    alarms_daily: {:#?},\n", self.alarms_daily)?;
+        writeln!(f, "    array_faults_daily: {:#?},", self.array_faults_daily)?;
+        writeln!(f, "    load_faults_daily: {:#?},", self.load_faults_daily)?;
+        writeln!(f, "    alarms_daily: {:#?},", self.alarms_daily)?;
         as_unit!(f, self, array_voltage_max_daily, volt)?;
         as_unit!(f, self, array_voltage_fixed, volt)?;
-        write!(f, "    array_voc_percent_fixed: {:.2},\n", self.array_voc_percent_fixed)?;
+        writeln!(f, "    array_voc_percent_fixed: {:.2},", self.array_voc_percent_fixed)?;
+        writeln!(f, "    calibrated: {},", self.calibrated)?;
+        let mut extras = self.extras.iter().collect::<Vec<_>>();
+        extras.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, v) in extras {
+            writeln!(f, "    {}: {},", name, v)?;
+        }
         write!(f, "}}")?;
         Ok(())
     }
 }
 
+/// One problem found by `Settings::violations`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ValidationError {
+    /// `value`, in `unit`, isn't between `min` and `max` inclusive
+    OutOfRange { field: String, value: f32, min: f32, max: f32, unit: String },
+    /// the `fields` can't be set like this together
+    Inconsistent { fields: Vec<String>, reason: String },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::OutOfRange { field, value, min, max, unit } => {
+                let unit =
+                    if unit.is_empty() { String::new() } else { format!(" {}", unit) };
+                write!(f, "{} {}{} outside {} <= x <= {}", field, value, unit, min, max)
+            }
+            ValidationError::Inconsistent { reason, .. } => write!(f, "{}", reason),
+        }
+    }
+}
+
+/** The error returned when settings fail validation, listing every
+problem found rather than just the first. Get at the list with
+`e.downcast_ref::<Invalid>()`. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invalid(pub Vec<ValidationError>);
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { "" } else { ", " }, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Invalid {}
+
 /** Device configuration settings */
 #[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Settings {
@@ -498,16 +763,89 @@ pub struct Settings {
 }
 
 macro_rules! validate {
-    ($o:ident, $field:ident, $unit:ident, $min:expr, $max:expr) => {
-        if $o.$field < $unit($min) || $o.$field > $unit($max) {
-            bail!("{} {} <= x <= {}", stringify!($field), $min, $max)
+    ($o:ident, $only:ident, $errs:ident, $field:ident, $unit:ident, $min:expr, $max:expr) => {
+        validate!(
+            $o,
+            $only,
+            $errs,
+            $field,
+            $o.$field.get::<$unit>(),
+            $unit::abbreviation(),
+            $min,
+            $max
+        )
+    };
+    ($o:ident, $only:ident, $errs:ident, $field:ident, $value:expr, $unit:expr, $min:expr, $max:expr) => {
+        if $only.map(|f| f == stringify!($field)).unwrap_or(true) {
+            let value = $value;
+            if !($min..=$max).contains(&value) {
+                $errs.push(ValidationError::OutOfRange {
+                    field: stringify!($field).into(),
+                    value,
+                    min: $min,
+                    max: $max,
+                    unit: $unit.into(),
+                })
+            }
+        }
+    };
+}
+
+/// Calls `$m` with the setter name, field and type of every writable
+/// setting but the modbus id
+macro_rules! with_settings {
+    ($m:ident) => {
+        $m! {
+            set_regulation_voltage, regulation_voltage: ElectricPotential;
+            set_float_voltage, float_voltage: ElectricPotential;
+            set_time_before_float, time_before_float: Time;
+            set_time_before_float_low_battery, time_before_float_low_battery: Time;
+            set_float_low_battery_voltage_trigger, float_low_battery_voltage_trigger: ElectricPotential;
+            set_float_cancel_voltage, float_cancel_voltage: ElectricPotential;
+            set_exit_float_time, exit_float_time: Time;
+            set_equalize_voltage, equalize_voltage: ElectricPotential;
+            set_days_between_equalize_cycles, days_between_equalize_cycles: Time;
+            set_equalize_time_limit_above_regulation_voltage,
+                equalize_time_limit_above_regulation_voltage: Time;
+            set_equalize_time_limit_at_regulation_voltage,
+                equalize_time_limit_at_regulation_voltage: Time;
+            set_alarm_on_setting_change, alarm_on_setting_change: bool;
+            set_reference_charge_voltage_limit, reference_charge_voltage_limit: ElectricPotential;
+            set_battery_charge_current_limit, battery_charge_current_limit: ElectricCurrent;
+            set_temperature_compensation_coefficent,
+                temperature_compensation_coefficent: ElectricPotential;
+            set_high_voltage_disconnect, high_voltage_disconnect: ElectricPotential;
+            set_high_voltage_reconnect, high_voltage_reconnect: ElectricPotential;
+            set_maximum_charge_voltage_reference, maximum_charge_voltage_reference: ElectricPotential;
+            set_max_battery_temp_compensation_limit,
+                max_battery_temp_compensation_limit: ThermodynamicTemperature;
+            set_min_battery_temp_compensation_limit,
+                min_battery_temp_compensation_limit: ThermodynamicTemperature;
+            set_load_low_voltage_disconnect, load_low_voltage_disconnect: ElectricPotential;
+            set_load_low_voltage_reconnect, load_low_voltage_reconnect: ElectricPotential;
+            set_load_high_voltage_disconnect, load_high_voltage_disconnect: ElectricPotential;
+            set_load_high_voltage_reconnect, load_high_voltage_reconnect: ElectricPotential;
+            set_lvd_load_current_compensation, lvd_load_current_compensation: ElectricalResistance;
+            set_lvd_warning_timeout, lvd_warning_timeout: Time;
+            set_led_green_to_green_and_yellow_limit,
+                led_green_to_green_and_yellow_limit: ElectricPotential;
+            set_led_green_and_yellow_to_yellow_limit,
+                led_green_and_yellow_to_yellow_limit: ElectricPotential;
+            set_led_yellow_to_yellow_and_red_limit,
+                led_yellow_to_yellow_and_red_limit: ElectricPotential;
+            set_led_yellow_and_red_to_red_flashing_limit,
+                led_yellow_and_red_to_red_flashing_limit: ElectricPotential;
+            set_meterbus_id, meterbus_id: u8;
+            set_mppt_fixed_vmp, mppt_fixed_vmp: ElectricPotential;
+            set_mppt_fixed_vmp_percent, mppt_fixed_vmp_percent: f32;
+            set_charge_current_limit, charge_current_limit: ElectricCurrent;
         }
     };
 }
 
 impl fmt::Display for Settings {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Settings {{\n")?;
+        writeln!(f, "Settings {{")?;
         as_unit!(f, self, regulation_voltage, volt)?;
         as_unit!(f, self, float_voltage, volt)?;
         as_unit!(f, self, time_before_float, second)?;
@@ -519,7 +857,7 @@ impl fmt::Display for Settings {
         as_unit!(f, self, days_between_equalize_cycles, day)?;
         as_unit!(f, self, equalize_time_limit_above_regulation_voltage, second)?;
         as_unit!(f, self, equalize_time_limit_at_regulation_voltage, second)?;
-        write!(f, "    alarm_on_setting_change: {},\n", self.alarm_on_setting_change)?;
+        writeln!(f, "    alarm_on_setting_change: {},", self.alarm_on_setting_change)?;
         as_unit!(f, self, reference_charge_voltage_limit, volt)?;
         as_unit!(f, self, battery_charge_current_limit, ampere)?;
         as_unit!(f, self, temperature_compensation_coefficent, volt)?;
@@ -538,10 +876,10 @@ impl fmt::Display for Settings {
         as_unit!(f, self, led_green_and_yellow_to_yellow_limit, volt)?;
         as_unit!(f, self, led_yellow_to_yellow_and_red_limit, volt)?;
         as_unit!(f, self, led_yellow_and_red_to_red_flashing_limit, volt)?;
-        write!(f, "    modbus_id: {},\n", self.modbus_id)?;
-        write!(f, "    meterbus_id: {},\n", self.meterbus_id)?;
+        writeln!(f, "    modbus_id: {},", self.modbus_id)?;
+        writeln!(f, "    meterbus_id: {},", self.meterbus_id)?;
         as_unit!(f, self, mppt_fixed_vmp, volt)?;
-        write!(f, "    mppt_fixed_vmp_percent: {},\n", self.mppt_fixed_vmp_percent)?;
+        writeln!(f, "    mppt_fixed_vmp_percent: {},", self.mppt_fixed_vmp_percent)?;
         as_unit!(f, self, charge_current_limit, ampere)?;
         write!(f, "}}")?;
         Ok(())
@@ -549,49 +887,765 @@ impl fmt::Display for Settings {
 }
 
 impl Settings {
+    /// Build settings up field by field, see `SettingsBuilder`
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder(Settings {
+            max_battery_temp_compensation_limit: c(0.),
+            min_battery_temp_compensation_limit: c(0.),
+            modbus_id: 1,
+            meterbus_id: 1,
+            ..Settings::default()
+        })
+    }
+
+    /// Fail with an `Invalid` error listing every violation if there
+    /// are any
     pub fn validate(&self) -> Result<()> {
-        validate!(self, regulation_voltage, v, 0., 17.5);
-        validate!(self, float_voltage, v, 0., 17.5);
-        validate!(self, time_before_float, sec, 0., 65535.);
-        validate!(self, time_before_float_low_battery, sec, 0., 65535.);
-        validate!(self, float_low_battery_voltage_trigger, v, 0., 17.5);
-        validate!(self, float_cancel_voltage, v, 0., 17.5);
-        validate!(self, exit_float_time, sec, 0., 65535.);
-        validate!(self, equalize_voltage, v, 0., 17.5);
-        validate!(self, days_between_equalize_cycles, dy, 0., 255.);
-        validate!(self, equalize_time_limit_above_regulation_voltage, sec, 0., 65535.);
-        validate!(self, equalize_time_limit_at_regulation_voltage, sec, 0., 65535.);
-        validate!(self, reference_charge_voltage_limit, v, 0., 17.5);
-        validate!(self, battery_charge_current_limit, a, 0., 40.);
-        validate!(self, temperature_compensation_coefficent, v, 0., 17.5);
-        validate!(self, high_voltage_disconnect, v, 0., 17.5);
-        validate!(self, high_voltage_reconnect, v, 0., 17.5);
-        validate!(self, maximum_charge_voltage_reference, v, 0., 17.5);
-        validate!(self, max_battery_temp_compensation_limit, c, -128., 127.);
-        validate!(self, min_battery_temp_compensation_limit, c, -128., 127.);
-        validate!(self, load_low_voltage_disconnect, v, 0., 17.5);
-        validate!(self, load_low_voltage_reconnect, v, 0., 17.5);
-        validate!(self, load_high_voltage_disconnect, v, 0., 17.5);
-        validate!(self, load_high_voltage_reconnect, v, 0., 17.5);
-        validate!(self, lvd_load_current_compensation, om, 0., 10000.);
-        validate!(self, lvd_warning_timeout, sec, 0., 65535.);
-        validate!(self, led_green_to_green_and_yellow_limit, v, 0., 17.5);
-        validate!(self, led_green_and_yellow_to_yellow_limit, v, 0., 17.5);
-        validate!(self, led_yellow_to_yellow_and_red_limit, v, 0., 17.5);
-        validate!(self, led_yellow_and_red_to_red_flashing_limit, v, 0., 17.5);
-        if self.modbus_id < 1 || self.modbus_id > 247 {
-            bail!("modbus_id 1 <= x <= 247");
-        }
-        if self.meterbus_id < 1 || self.meterbus_id > 15 {
-            bail!("meterbus_id 1 <= x <= 15");
-        }
-        validate!(self, mppt_fixed_vmp, v, 0., 120.);
-        if self.mppt_fixed_vmp_percent < 0. || self.mppt_fixed_vmp_percent > 1. {
-            bail!("mppt_fixed_vmp_percent 0 <= x <= 1")
-        }
-        validate!(self, charge_current_limit, a, 0., 40.);
+        let errs = self.violations();
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(Invalid(errs).into())
+        }
+    }
+
+    /// Every field that is out of range or inconsistent with another
+    pub fn violations(&self) -> Vec<ValidationError> {
+        let mut errs = self.validate_fields(None);
+        if let Err(e) = self.tracking_mode() {
+            errs.push(ValidationError::Inconsistent {
+                fields: vec!["mppt_fixed_vmp".into(), "mppt_fixed_vmp_percent".into()],
+                reason: e.to_string(),
+            })
+        }
+        errs
+    }
+
+    /// The range violations of the field named `only`, or of every field
+    fn validate_fields(&self, only: Option<&str>) -> Vec<ValidationError> {
+        let mut errs = Vec::new();
+        validate!(self, only, errs, regulation_voltage, volt, 0., 17.5);
+        validate!(self, only, errs, float_voltage, volt, 0., 17.5);
+        validate!(self, only, errs, time_before_float, second, 0., 65535.);
+        validate!(self, only, errs, time_before_float_low_battery, second, 0., 65535.);
+        validate!(self, only, errs, float_low_battery_voltage_trigger, volt, 0., 17.5);
+        validate!(self, only, errs, float_cancel_voltage, volt, 0., 17.5);
+        validate!(self, only, errs, exit_float_time, second, 0., 65535.);
+        validate!(self, only, errs, equalize_voltage, volt, 0., 17.5);
+        validate!(self, only, errs, days_between_equalize_cycles, day, 0., 255.);
+        validate!(
+            self,
+            only,
+            errs,
+            equalize_time_limit_above_regulation_voltage,
+            second,
+            0.,
+            65535.
+        );
+        validate!(
+            self,
+            only,
+            errs,
+            equalize_time_limit_at_regulation_voltage,
+            second,
+            0.,
+            65535.
+        );
+        validate!(self, only, errs, reference_charge_voltage_limit, volt, 0., 17.5);
+        validate!(self, only, errs, battery_charge_current_limit, ampere, 0., 40.);
+        validate!(self, only, errs, temperature_compensation_coefficent, volt, 0., 17.5);
+        validate!(self, only, errs, high_voltage_disconnect, volt, 0., 17.5);
+        validate!(self, only, errs, high_voltage_reconnect, volt, 0., 17.5);
+        validate!(self, only, errs, maximum_charge_voltage_reference, volt, 0., 17.5);
+        validate!(
+            self,
+            only,
+            errs,
+            max_battery_temp_compensation_limit,
+            degree_celsius,
+            -128.,
+            127.
+        );
+        validate!(
+            self,
+            only,
+            errs,
+            min_battery_temp_compensation_limit,
+            degree_celsius,
+            -128.,
+            127.
+        );
+        validate!(self, only, errs, load_low_voltage_disconnect, volt, 0., 17.5);
+        validate!(self, only, errs, load_low_voltage_reconnect, volt, 0., 17.5);
+        validate!(self, only, errs, load_high_voltage_disconnect, volt, 0., 17.5);
+        validate!(self, only, errs, load_high_voltage_reconnect, volt, 0., 17.5);
+        validate!(self, only, errs, lvd_load_current_compensation, ohm, 0., 10000.);
+        validate!(self, only, errs, lvd_warning_timeout, second, 0., 65535.);
+        validate!(self, only, errs, led_green_to_green_and_yellow_limit, volt, 0., 17.5);
+        validate!(self, only, errs, led_green_and_yellow_to_yellow_limit, volt, 0., 17.5);
+        validate!(self, only, errs, led_yellow_to_yellow_and_red_limit, volt, 0., 17.5);
+        validate!(
+            self,
+            only,
+            errs,
+            led_yellow_and_red_to_red_flashing_limit,
+            volt,
+            0.,
+            17.5
+        );
+        validate!(self, only, errs, modbus_id, self.modbus_id as f32, "", 1., 247.);
+        validate!(self, only, errs, meterbus_id, self.meterbus_id as f32, "", 1., 15.);
+        validate!(self, only, errs, mppt_fixed_vmp, volt, 0., 120.);
+        let pct = self.mppt_fixed_vmp_percent;
+        validate!(self, only, errs, mppt_fixed_vmp_percent, pct, "", 0., 1.);
+        validate!(self, only, errs, charge_current_limit, ampere, 0., 40.);
+        errs
+    }
+
+    /// The tracking mode selected by mppt_fixed_vmp and
+    /// mppt_fixed_vmp_percent. It is an error for both to be set.
+    pub fn tracking_mode(&self) -> Result<TrackingMode> {
+        let vmp = self.mppt_fixed_vmp.get::<volt>();
+        let pct = self.mppt_fixed_vmp_percent;
+        if vmp != 0. && pct != 0. {
+            bail!("mppt_fixed_vmp and mppt_fixed_vmp_percent are both set")
+        } else if vmp != 0. {
+            Ok(TrackingMode::FixedVoltage(self.mppt_fixed_vmp))
+        } else if pct != 0. {
+            Ok(TrackingMode::PercentVoc(pct))
+        } else {
+            Ok(TrackingMode::Mppt)
+        }
+    }
+
+    /// Set the charging fields for a battery type, leaving the rest
+    /// alone, see `preset`
+    pub fn apply_preset(&mut self, profile: preset::BatteryProfile) {
+        profile.apply(self)
+    }
+
+    /// Set mppt_fixed_vmp and mppt_fixed_vmp_percent together for
+    /// `mode`, leaving the settings unchanged if it is out of range.
+    pub fn set_tracking_mode(&mut self, mode: TrackingMode) -> Result<()> {
+        let (vmp, pct) = match mode {
+            TrackingMode::Mppt => (v(0.), 0.),
+            TrackingMode::FixedVoltage(vmp) => {
+                if vmp <= v(0.) || vmp > v(120.) {
+                    bail!("fixed tracking voltage 0 < x <= 120")
+                }
+                (vmp, 0.)
+            }
+            TrackingMode::PercentVoc(pct) => {
+                if pct <= 0. || pct > 1. {
+                    bail!("fixed tracking fraction of Voc 0 < x <= 1")
+                }
+                (v(0.), pct)
+            }
+        };
+        self.mppt_fixed_vmp = vmp;
+        self.mppt_fixed_vmp_percent = pct;
         Ok(())
     }
+
+    /// True if `self` and `other` would be stored as the same register
+    /// values, ignoring differences finer than the f16 encoding (see
+    /// `codec`). Settings read back after a write compare equal to
+    /// what was written.
+    pub fn quantized_eq(&self, other: &Settings) -> bool {
+        self.registers() == other.registers()
+    }
+
+    /// The registers of `new` whose value differs from `self`
+    fn delta(&self, new: &Settings) -> Vec<(usize, &'static str, u16)> {
+        self.registers()
+            .iter()
+            .zip(new.registers().iter())
+            .filter(|(o, n)| o.2 != n.2)
+            .map(|(_, n)| *n)
+            .collect()
+    }
+
+    /// The settings whose register in `cur`, read from `SETTINGS_BASE`
+    /// on, differs from its value in `self`
+    fn unapplied(&self, cur: &[u16]) -> Vec<Unapplied> {
+        unapplied(&self.registers(), SETTINGS_BASE, cur)
+    }
+
+    /// The address, name and encoded value of every writable setting
+    fn registers(&self) -> [(usize, &'static str, u16); 35] {
+        [
+            (0xE000, "regulation_voltage", to_v(self.regulation_voltage)),
+            (0xE001, "float_voltage", to_v(self.float_voltage)),
+            (0xE002, "time_before_float", to_sec(self.time_before_float)),
+            (
+                0xE003,
+                "time_before_float_low_battery",
+                to_sec(self.time_before_float_low_battery),
+            ),
+            (
+                0xE004,
+                "float_low_battery_voltage_trigger",
+                to_v(self.float_low_battery_voltage_trigger),
+            ),
+            (0xE005, "float_cancel_voltage", to_v(self.float_cancel_voltage)),
+            (0xE006, "exit_float_time", to_sec(self.exit_float_time)),
+            (0xE007, "equalize_voltage", to_v(self.equalize_voltage)),
+            (
+                0xE008,
+                "days_between_equalize_cycles",
+                to_dy(self.days_between_equalize_cycles),
+            ),
+            (
+                0xE009,
+                "equalize_time_limit_above_regulation_voltage",
+                to_sec(self.equalize_time_limit_above_regulation_voltage),
+            ),
+            (
+                0xE00A,
+                "equalize_time_limit_at_regulation_voltage",
+                to_sec(self.equalize_time_limit_at_regulation_voltage),
+            ),
+            (
+                0xE00D,
+                "alarm_on_setting_change",
+                if self.alarm_on_setting_change { 1 } else { 0 },
+            ),
+            (
+                0xE010,
+                "reference_charge_voltage_limit",
+                to_v(self.reference_charge_voltage_limit),
+            ),
+            (
+                0xE013,
+                "battery_charge_current_limit",
+                to_a(self.battery_charge_current_limit),
+            ),
+            (
+                0xE01A,
+                "temperature_compensation_coefficent",
+                to_v(self.temperature_compensation_coefficent),
+            ),
+            (0xE01B, "high_voltage_disconnect", to_v(self.high_voltage_disconnect)),
+            (0xE01C, "high_voltage_reconnect", to_v(self.high_voltage_reconnect)),
+            (
+                0xE01D,
+                "maximum_charge_voltage_reference",
+                to_v(self.maximum_charge_voltage_reference),
+            ),
+            (
+                0xE01E,
+                "max_battery_temp_compensation_limit",
+                to_ic(self.max_battery_temp_compensation_limit),
+            ),
+            (
+                0xE01F,
+                "min_battery_temp_compensation_limit",
+                to_ic(self.min_battery_temp_compensation_limit),
+            ),
+            (
+                0xE022,
+                "load_low_voltage_disconnect",
+                to_v(self.load_low_voltage_disconnect),
+            ),
+            (0xE023, "load_low_voltage_reconnect", to_v(self.load_low_voltage_reconnect)),
+            (
+                0xE024,
+                "load_high_voltage_disconnect",
+                to_v(self.load_high_voltage_disconnect),
+            ),
+            (
+                0xE025,
+                "load_high_voltage_reconnect",
+                to_v(self.load_high_voltage_reconnect),
+            ),
+            (
+                0xE026,
+                "lvd_load_current_compensation",
+                to_om(self.lvd_load_current_compensation),
+            ),
+            (0xE027, "lvd_warning_timeout", to_mn(self.lvd_warning_timeout)),
+            (
+                0xE030,
+                "led_green_to_green_and_yellow_limit",
+                to_v(self.led_green_to_green_and_yellow_limit),
+            ),
+            (
+                0xE031,
+                "led_green_and_yellow_to_yellow_limit",
+                to_v(self.led_green_and_yellow_to_yellow_limit),
+            ),
+            (
+                0xE032,
+                "led_yellow_to_yellow_and_red_limit",
+                to_v(self.led_yellow_to_yellow_and_red_limit),
+            ),
+            (
+                0xE033,
+                "led_yellow_and_red_to_red_flashing_limit",
+                to_v(self.led_yellow_and_red_to_red_flashing_limit),
+            ),
+            (0xE034, "modbus_id", self.modbus_id as u16),
+            (0xE035, "meterbus_id", self.meterbus_id as u16),
+            (0xE036, "mppt_fixed_vmp", to_v(self.mppt_fixed_vmp)),
+            (
+                0xE037,
+                "mppt_fixed_vmp_percent",
+                f16::from_f32(self.mppt_fixed_vmp_percent).to_bits(),
+            ),
+            (0xE038, "charge_current_limit", to_a(self.charge_current_limit)),
+        ]
+    }
+}
+
+macro_rules! battery_voltages {
+    ($($field:ident),* $(,)?) => {
+        /// The settings that are battery voltages. The controller stores
+        /// them for a 12 V battery and multiplies them by
+        /// `Stats::battery_voltage_settings_multiplier`.
+        const BATTERY_VOLTAGES: &[&str] = &[$(stringify!($field)),*];
+
+        impl Settings {
+            /// Multiply every battery voltage by `k`
+            fn scale_battery_voltages(&self, k: f32) -> Settings {
+                let mut s = *self;
+                $(s.$field *= k;)*
+                s
+            }
+        }
+    };
+}
+
+battery_voltages!(
+    regulation_voltage,
+    float_voltage,
+    float_low_battery_voltage_trigger,
+    float_cancel_voltage,
+    equalize_voltage,
+    reference_charge_voltage_limit,
+    temperature_compensation_coefficent,
+    high_voltage_disconnect,
+    high_voltage_reconnect,
+    maximum_charge_voltage_reference,
+    load_low_voltage_disconnect,
+    load_low_voltage_reconnect,
+    load_high_voltage_disconnect,
+    load_high_voltage_reconnect,
+    led_green_to_green_and_yellow_limit,
+    led_green_and_yellow_to_yellow_limit,
+    led_yellow_to_yellow_and_red_limit,
+    led_yellow_and_red_to_red_flashing_limit,
+);
+
+impl Settings {
+    /// These settings, which like the registers are for a 12 V battery,
+    /// with the battery voltages in the system's own volts, e.g. a
+    /// regulation voltage of 28.8 V rather than 14.4 V for a 24 V system
+    pub fn to_system(&self, multiplier: u16) -> Settings {
+        self.scale_battery_voltages(multiplier.max(1) as f32)
+    }
+
+    /// The inverse of `to_system`, back to a 12 V basis
+    pub fn from_system(&self, multiplier: u16) -> Settings {
+        self.scale_battery_voltages(1. / multiplier.max(1) as f32)
+    }
+
+    /// `violations` of settings in system volts, see `to_system`. The
+    /// values and ranges reported are in system volts too.
+    pub fn system_violations(&self, multiplier: u16) -> Vec<ValidationError> {
+        let k = multiplier.max(1) as f32;
+        let mut errs = self.from_system(multiplier).violations();
+        for e in errs.iter_mut() {
+            if let ValidationError::OutOfRange { field, value, min, max, .. } = e {
+                if BATTERY_VOLTAGES.contains(&field.as_str()) {
+                    *value *= k;
+                    *min *= k;
+                    *max *= k;
+                }
+            }
+        }
+        errs
+    }
+
+    /// `validate` for settings in system volts, see `to_system`
+    pub fn validate_system(&self, multiplier: u16) -> Result<()> {
+        let errs = self.system_violations(multiplier);
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(Invalid(errs).into())
+        }
+    }
+}
+
+/// Schema version written by `Settings::to_file`
+const SETTINGS_FILE_VERSION: u32 = 1;
+
+/// How a setting is written to a settings file
+trait FileValue: Sized {
+    type Repr: serde::Serialize + serde::de::DeserializeOwned;
+
+    fn to_repr(self) -> Self::Repr;
+    fn from_repr(repr: Self::Repr) -> Result<Self>;
+}
+
+// the shortest decimal that reads back as `v`, rather than the f32
+// widened to f64, which would write 14.4 as 14.399999618530273
+fn short(v: f32) -> f64 {
+    v.to_string().parse().unwrap_or(v as f64)
+}
+
+macro_rules! file_value {
+    ($typ:ty, $unit:ident) => {
+        impl FileValue for $typ {
+            type Repr = f64;
+
+            fn to_repr(self) -> f64 {
+                short(self.get::<$unit>())
+            }
+
+            fn from_repr(repr: f64) -> Result<Self> {
+                Ok(<$typ>::new::<$unit>(repr as f32))
+            }
+        }
+    };
+    ($typ:ty) => {
+        impl FileValue for $typ {
+            type Repr = $typ;
+
+            fn to_repr(self) -> $typ {
+                self
+            }
+
+            fn from_repr(repr: $typ) -> Result<Self> {
+                Ok(repr)
+            }
+        }
+    };
+}
+
+file_value!(ElectricPotential, volt);
+file_value!(ElectricCurrent, ampere);
+file_value!(ElectricalResistance, ohm);
+file_value!(ThermodynamicTemperature, degree_celsius);
+file_value!(bool);
+file_value!(u8);
+
+impl FileValue for f32 {
+    type Repr = f64;
+
+    fn to_repr(self) -> f64 {
+        short(self)
+    }
+
+    fn from_repr(repr: f64) -> Result<Self> {
+        Ok(repr as f32)
+    }
+}
+
+const TIME_UNITS: [(char, u64); 4] = [('d', 86400), ('h', 3600), ('m', 60), ('s', 1)];
+
+/// Times are written as days, hours, minutes and seconds, e.g. "28d",
+/// "2h30m" or "90s"
+impl FileValue for Time {
+    type Repr = String;
+
+    fn to_repr(self) -> String {
+        let mut secs = self.get::<second>().round().max(0.) as u64;
+        if secs == 0 {
+            return "0s".into();
+        }
+        let mut res = String::new();
+        for (unit, len) in TIME_UNITS.iter() {
+            if secs >= *len {
+                res.push_str(&format!("{}{}", secs / len, unit));
+                secs %= len;
+            }
+        }
+        res
+    }
+
+    fn from_repr(repr: String) -> Result<Self> {
+        let mut secs = 0.;
+        let mut rest = repr.trim();
+        if rest.is_empty() {
+            bail!("empty time")
+        }
+        while !rest.is_empty() {
+            let n = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let num: f32 =
+                rest[..n].parse().with_context(|| format!("invalid time {:?}", repr))?;
+            rest = rest[n..].trim_start();
+            let unit = rest.chars().next();
+            let len = match TIME_UNITS.iter().find(|(u, _)| Some(*u) == unit) {
+                Some((_, len)) => *len,
+                None => bail!("invalid time {:?}, expected units of d, h, m or s", repr),
+            };
+            secs += num * len as f32;
+            rest = rest[1..].trim_start();
+        }
+        Ok(sec(secs))
+    }
+}
+
+macro_rules! settings_file {
+    ($($set:ident, $field:ident: $typ:ty;)*) => {
+        #[derive(Serialize, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct SettingsFile {
+            version: u32,
+            modbus_id: u8,
+            $($field: <$typ as FileValue>::Repr,)*
+        }
+
+        impl From<&Settings> for SettingsFile {
+            fn from(s: &Settings) -> SettingsFile {
+                SettingsFile {
+                    version: SETTINGS_FILE_VERSION,
+                    modbus_id: s.modbus_id,
+                    $($field: s.$field.to_repr(),)*
+                }
+            }
+        }
+
+        impl SettingsFile {
+            fn settings(self) -> Result<Settings> {
+                Ok(Settings {
+                    modbus_id: self.modbus_id,
+                    $(
+                        $field: FileValue::from_repr(self.$field)
+                            .context(stringify!($field))?,
+                    )*
+                })
+            }
+        }
+    };
+}
+
+with_settings!(settings_file);
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().map(|e| e == "toml").unwrap_or(false)
+}
+
+impl Settings {
+    /** Save the settings to a file meant to be read and edited by
+    people, and kept under version control. A path ending in `.toml`
+    gets TOML, which needs the `toml` feature, anything else JSON.
+    Fields are named as in `Settings`. Voltages, currents, resistances
+    and temperatures are plain numbers in volts, amps, ohms and °C.
+    Times are strings of days, hours, minutes and seconds, e.g. "28d",
+    "2h30m" or "90s". The schema is versioned, so a file written by
+    one version of this library can be read by later ones.
+
+    # Examples
+    ```no_run
+    use morningstar::prostar_mppt::{self as ps, Settings};
+    # async fn run() -> anyhow::Result<()> {
+
+    let con = ps::Connection::new("/dev/ttyUSB0", 1).await?;
+    con.read_settings().await?.to_file("settings.json")?;
+    // after a factory reset
+    con.write_settings(&Settings::from_file("settings.json")?).await?;
+    # Ok(())
+    # }
+    ```
+    */
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let f = SettingsFile::from(self);
+        if is_toml(path) {
+            #[cfg(feature = "toml")]
+            {
+                let s =
+                    toml::to_string_pretty(&f).context("failed to encode settings")?;
+                std::fs::write(path, s).context("failed to write settings file")
+            }
+            #[cfg(not(feature = "toml"))]
+            bail!("TOML settings files need the toml feature")
+        } else {
+            let file = File::create(path).context("failed to create settings file")?;
+            serde_json::to_writer_pretty(file, &f)
+                .context("failed to write settings file")
+        }
+    }
+
+    /// Load settings saved by `to_file`, as TOML if the path ends in
+    /// `.toml`, otherwise JSON. Every field must be present, and the
+    /// settings are validated, failing with an `Invalid` error if an
+    /// edit put any of them out of range.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Settings> {
+        let path = path.as_ref();
+        let f: SettingsFile = if is_toml(path) {
+            #[cfg(feature = "toml")]
+            {
+                let s = std::fs::read_to_string(path)
+                    .context("failed to open settings file")?;
+                toml::from_str(&s).context("failed to parse settings file")?
+            }
+            #[cfg(not(feature = "toml"))]
+            bail!("TOML settings files need the toml feature")
+        } else {
+            let file = File::open(path).context("failed to open settings file")?;
+            serde_json::from_reader(BufReader::new(file))
+                .context("failed to parse settings file")?
+        };
+        if f.version != SETTINGS_FILE_VERSION {
+            bail!("unsupported settings file version {}", f.version)
+        }
+        let settings = f.settings().context("invalid settings file")?;
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/** Settings built up one field at a time, each checked against its
+range as it is set. `build` then checks that the fields agree with
+each other, e.g. that float is below regulation and each reconnect
+voltage is on the right side of its disconnect, which `validate`
+doesn't. Start from `Settings::builder()`, where both ids are 1 and
+everything else is zero (0°C for temperatures), or from settings read
+from the controller with `SettingsBuilder::from`.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, Settings};
+use uom::si::{electric_potential::volt, f32::ElectricPotential};
+# async fn run() -> anyhow::Result<()> {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await?;
+let v = ElectricPotential::new::<volt>;
+let settings = ps::SettingsBuilder::from(con.read_settings().await?)
+    .regulation_voltage(v(14.4))?
+    .float_voltage(v(13.6))?
+    .build()?;
+con.write_settings(&settings).await?;
+# Ok(())
+# }
+```
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct SettingsBuilder(Settings);
+
+impl From<Settings> for SettingsBuilder {
+    fn from(settings: Settings) -> SettingsBuilder {
+        SettingsBuilder(settings)
+    }
+}
+
+macro_rules! builder_setters {
+    ($($set:ident, $field:ident: $typ:ty;)*) => {
+        impl SettingsBuilder {
+            $(
+                #[doc = concat!(
+                    "Set `", stringify!($field), "`, failing if it is out of range"
+                )]
+                pub fn $field(mut self, v: $typ) -> Result<Self> {
+                    self.0.$field = v;
+                    self.check(stringify!($field))
+                }
+            )*
+        }
+    };
+}
+
+with_settings!(builder_setters);
+
+macro_rules! conflict {
+    ($s:ident, $errs:ident, $a:ident $op:tt $b:ident, $reason:expr) => {
+        if $s.$a $op $s.$b {
+            $errs.push(ValidationError::Inconsistent {
+                fields: vec![stringify!($a).into(), stringify!($b).into()],
+                reason: format!("{} {} {}", stringify!($a), $reason, stringify!($b)),
+            })
+        }
+    };
+}
+
+impl SettingsBuilder {
+    fn check(self, field: &str) -> Result<Self> {
+        let errs = self.0.validate_fields(Some(field));
+        if errs.is_empty() {
+            Ok(self)
+        } else {
+            Err(Invalid(errs).into())
+        }
+    }
+
+    /// Set the modbus id, failing if it is out of range
+    pub fn modbus_id(mut self, id: u8) -> Result<Self> {
+        self.0.modbus_id = id;
+        self.check("modbus_id")
+    }
+
+    /// Set the charging fields for a battery type, see `preset`
+    pub fn preset(mut self, profile: preset::BatteryProfile) -> Self {
+        profile.apply(&mut self.0);
+        self
+    }
+
+    /// Set mppt_fixed_vmp and mppt_fixed_vmp_percent together, see
+    /// `Settings::set_tracking_mode`
+    pub fn tracking_mode(mut self, mode: TrackingMode) -> Result<Self> {
+        self.0.set_tracking_mode(mode)?;
+        Ok(self)
+    }
+
+    /// Validate the settings as a whole and check that related fields
+    /// agree with each other, failing with an `Invalid` error listing
+    /// every problem found
+    pub fn build(self) -> Result<Settings> {
+        let s = self.0;
+        let mut errs = s.violations();
+        let below = "must be below";
+        conflict!(s, errs, float_voltage > regulation_voltage, "must not be above");
+        conflict!(s, errs, high_voltage_reconnect >= high_voltage_disconnect, below);
+        conflict!(
+            s,
+            errs,
+            load_low_voltage_disconnect >= load_low_voltage_reconnect,
+            below
+        );
+        conflict!(
+            s,
+            errs,
+            load_high_voltage_reconnect >= load_high_voltage_disconnect,
+            below
+        );
+        conflict!(
+            s,
+            errs,
+            min_battery_temp_compensation_limit >= max_battery_temp_compensation_limit,
+            below
+        );
+        if errs.is_empty() {
+            Ok(s)
+        } else {
+            Err(Invalid(errs).into())
+        }
+    }
+}
+
+/** How the controller picks the array operating voltage */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum TrackingMode {
+    /// maximum power point tracking, both fixed settings are zero
+    Mppt,
+    /// hold the array at a fixed voltage (mppt_fixed_vmp)
+    FixedVoltage(ElectricPotential),
+    /// hold the array at a fraction of Voc (mppt_fixed_vmp_percent)
+    PercentVoc(f32),
+}
+
+impl fmt::Display for TrackingMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackingMode::Mppt => write!(f, "MPPT"),
+            TrackingMode::FixedVoltage(v) => write!(f, "fixed {:.2} V", v.get::<volt>()),
+            TrackingMode::PercentVoc(p) => write!(f, "fixed {:.1}% of Voc", p * 100.),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -633,133 +1687,1403 @@ impl Coil {
     }
 }
 
-/** Device connection. */
-pub struct Connection(Modbus);
+fn is_illegal_data_address(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Other && e.to_string().contains("Illegal data address")
+}
 
-impl Connection {
-    pub async fn new(device: &str, modbus_id: u8) -> Result<Connection> {
-        let port = SerialStream::open(
-            &tokio_serial::new(device, 9600)
-                .data_bits(DataBits::Eight)
-                .flow_control(FlowControl::None)
-                .parity(Parity::None)
-                .stop_bits(StopBits::Two)
-                .timeout(Duration::from_secs(10)),
-        )
-        .context("failed to connect to serial port")?;
-        let con = rtu::connect_slave(port, Slave(modbus_id))
-            .await
-            .context("failed to build modbus context")?;
-        Ok(Connection(con))
+fn is_illegal_function(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Other && e.to_string().contains("Illegal function")
+}
+
+/** The error returned when a request takes longer than the
+connection's request timeout. Tell it apart from other failures with
+`e.is::<Timeout>()` or `e.downcast_ref::<Timeout>()`. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout(pub Duration);
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request timed out after {:?}", self.0)
     }
+}
 
-    pub async fn read_coil(&mut self, coil: Coil) -> Result<bool> {
-        let res =
-            self.0.read_coils(coil.address(), 1).await.context("read coil failed")?;
+impl error::Error for Timeout {}
+
+/// A setting that read back different from what was written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unapplied {
+    pub address: u16,
+    /// the `Settings` field
+    pub name: String,
+    /// the encoded value written
+    pub written: u16,
+    /// the encoded value read back
+    pub read: u16,
+}
+
+/// The `registers` whose value differs in `cur`, read from `base` on
+fn unapplied(
+    registers: &[(usize, &'static str, u16)],
+    base: usize,
+    cur: &[u16],
+) -> Vec<Unapplied> {
+    registers
+        .iter()
+        .filter_map(|(addr, name, val)| match cur.get(addr - base) {
+            Some(read) if read != val => Some(Unapplied {
+                address: *addr as u16,
+                name: (*name).into(),
+                written: *val,
+                read: *read,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/** The error returned when settings read back after a write don't
+match what was written, e.g. because the firmware rejected a value
+as out of range without an exception. The comparison is of the
+encoded registers, so f16 rounding isn't reported. Tell it apart from
+other failures with `e.is::<NotApplied>()` or
+`e.downcast_ref::<NotApplied>()`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotApplied(pub Vec<Unapplied>);
+
+impl fmt::Display for NotApplied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "settings did not keep their new values:")?;
+        for (i, u) in self.0.iter().enumerate() {
+            write!(
+                f,
+                "{} {} ({:#06X}) wrote {:#06X} read {:#06X}",
+                if i == 0 { "" } else { "," },
+                u.name,
+                u.address,
+                u.written,
+                u.read
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for NotApplied {}
+
+impl NotApplied {
+    /// Fail with `NotApplied` unless `unapplied` is empty
+    fn check(unapplied: Vec<Unapplied>) -> Result<()> {
+        if unapplied.is_empty() {
+            Ok(())
+        } else {
+            Err(NotApplied(unapplied).into())
+        }
+    }
+}
+
+/// Scratch space for `Connection::stats_into`, holding the raw
+/// registers of the last read
+#[derive(Debug, Clone, Default)]
+pub struct RegisterBuf(Vec<u16>);
+
+impl RegisterBuf {
+    pub fn new() -> RegisterBuf {
+        RegisterBuf(Vec::with_capacity(STATS_LEN))
+    }
+
+    /// The raw registers, starting at address 0
+    pub fn registers(&self) -> &[u16] {
+        &self.0
+    }
+}
+
+/** How a `Connection` reopens its port after the transport fails,
+e.g. a USB adapter re-enumerating or a gateway dropping the socket.
+The operation that hit the failure still returns its error, the next
+one reopens the port first, waiting `initial_delay` after the first
+failed attempt and doubling the wait up to `max_delay` after each
+one after that. */
+#[derive(Debug, Clone, Copy)]
+pub struct Reconnect {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// give up and return an error after this many failed attempts,
+    /// the next operation starts over. None to retry forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Reconnect {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .checked_mul(1 << attempt.min(16))
+            .map(|d| d.min(self.max_delay))
+            .unwrap_or(self.max_delay)
+    }
+}
+
+/** How long to wait for a controller to settle after power up or a
+reset. For a few seconds it may not answer, or answer with garbage,
+so `Connection::warm_up` reads stats every `interval` until
+`clean_reads` reads in a row pass `conformance::plausible`, and fails
+if that hasn't happened within `timeout`. */
+#[derive(Debug, Clone, Copy)]
+pub struct WarmUp {
+    pub clean_reads: usize,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for WarmUp {
+    fn default() -> Self {
+        WarmUp {
+            clean_reads: 3,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/** RTS direction control for RS-485 adapters that don't switch
+between transmit and receive on their own. RTS is asserted
+`pre_delay` before each request and released `post_delay` after the
+last byte has left the port, as timed from the port's baud rate and
+framing. */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rts {
+    pub pre_delay: Duration,
+    pub post_delay: Duration,
+}
+
+type Opener = Box<dyn Fn() -> BoxFuture<'static, Result<Connection>> + Send + Sync>;
+
+/// The state of a transport, shared with the connections using it so
+/// it can be updated without taking the link
+#[derive(Debug, Default)]
+struct Wire {
+    // failed and needs reopening
+    broken: AtomicBool,
+    // a frame has been written since the current request got the bus
+    sent: AtomicBool,
+}
+
+tokio::task_local! {
+    // the wire a request running under `Connection::timed` got the bus
+    // for, so the timeout can start then
+    static ACQUIRED: Arc<std::sync::Mutex<Option<Arc<Wire>>>>;
+}
+
+/// Sets `broken` when the transport fails or reaches end of file, and
+/// fails a read when a response stalls for longer than `char_timeout`
+/// part way through
+#[derive(Debug)]
+struct Watched<T> {
+    inner: T,
+    wire: Arc<Wire>,
+    char_timeout: Option<Duration>,
+    // bytes of a response have arrived since the last write
+    mid_frame: bool,
+    char_timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> Watched<T> {
+    fn new(inner: T, char_timeout: Option<Duration>) -> Watched<T> {
+        Watched {
+            inner,
+            wire: Arc::new(Wire::default()),
+            char_timeout,
+            mid_frame: false,
+            char_timer: None,
+        }
+    }
+}
+
+impl<T> Watched<T> {
+    fn check<R>(&self, r: Poll<io::Result<R>>) -> Poll<io::Result<R>> {
+        if let Poll::Ready(Err(_)) = &r {
+            self.wire.broken.store(true, Ordering::Relaxed);
+        }
+        r
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Watched<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let (filled, remaining) = (buf.filled().len(), buf.remaining());
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        match r {
+            Poll::Ready(Ok(())) => {
+                if remaining > 0 && buf.filled().len() == filled {
+                    self.wire.broken.store(true, Ordering::Relaxed);
+                }
+                self.mid_frame = true;
+                self.char_timer = None;
+            }
+            Poll::Pending if self.mid_frame => {
+                if let Some(timeout) = self.char_timeout {
+                    let timer = self
+                        .char_timer
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                    if timer.as_mut().poll(cx).is_ready() {
+                        self.mid_frame = false;
+                        self.char_timer = None;
+                        let e = io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "inter-character timeout, response incomplete",
+                        );
+                        return self.check(Poll::Ready(Err(e)));
+                    }
+                }
+            }
+            _ => (),
+        }
+        self.check(r)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Watched<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.mid_frame = false;
+        self.char_timer = None;
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = r {
+            if n > 0 {
+                self.wire.sent.store(true, Ordering::Relaxed);
+            }
+        }
+        self.check(r)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<io::Result<()>> {
+        let r = Pin::new(&mut self.inner).poll_flush(cx);
+        self.check(r)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<io::Result<()>> {
+        let r = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.check(r)
+    }
+}
+
+/// How frames are encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Framing {
+    Rtu,
+    /// MODBUS ASCII, for legacy gateways that only speak it
+    Ascii,
+}
+
+/** Serial port settings for `Connection`. The defaults are what
+the controller ships with, 9600 baud 8N2. */
+#[derive(Debug, Clone)]
+pub struct ConnectionBuilder {
+    device: String,
+    modbus_id: u8,
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    timeout: Duration,
+    framing: Framing,
+    frame_delay: Option<Duration>,
+    char_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    write_delay: Duration,
+    reconnect: Option<Reconnect>,
+    warm_up: Option<WarmUp>,
+    rts: Option<Rts>,
+}
+
+impl ConnectionBuilder {
+    pub fn new(device: &str, modbus_id: u8) -> ConnectionBuilder {
+        ConnectionBuilder {
+            device: device.into(),
+            modbus_id,
+            baud_rate: 9600,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::Two,
+            timeout: Duration::from_secs(10),
+            framing: Framing::Rtu,
+            frame_delay: None,
+            char_timeout: None,
+            request_timeout: None,
+            write_delay: WRITE_DELAY,
+            reconnect: None,
+            warm_up: None,
+            rts: None,
+        }
+    }
+
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    /// The serial port timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// RTU by default. ASCII gateways usually also want 7 data bits
+    /// and even parity, and allow up to a second between characters,
+    /// so leave `char_timeout` off or set it above that.
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// The minimum idle time on the bus between a response and the
+    /// next request, the RTU frame gap. By default it is the T3.5 the
+    /// standard asks for at the baud rate, 4 ms at 9600. Some clone
+    /// adapters need a lot more, try 20 ms if there are intermittent
+    /// CRC errors.
+    pub fn frame_delay(mut self, frame_delay: Duration) -> Self {
+        self.frame_delay = Some(frame_delay);
+        self
+    }
+
+    /// Fail a request when its response stops part way through for
+    /// longer than `char_timeout`, instead of waiting out the request
+    /// timeout and then mistaking the next response for the rest of
+    /// it. USB adapters deliver bytes in bursts, so set this well
+    /// above the standard T1.5, e.g. 50 ms. The port is marked failed,
+    /// so with `reconnect` set it is reopened and the stale bytes
+    /// discarded. Off by default.
+    pub fn char_timeout(mut self, char_timeout: Duration) -> Self {
+        self.char_timeout = Some(char_timeout);
+        self
+    }
+
+    // T3.5, 3.5 characters of 11 bits, fixed at 1.75 ms above 19200
+    fn t35(&self) -> Duration {
+        if self.baud_rate > 19200 {
+            Duration::from_micros(1750)
+        } else {
+            Duration::from_micros(38_500_000 / self.baud_rate.max(1) as u64)
+        }
+    }
+
+    /// See `Connection::request_timeout`. None by default.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Connection::write_delay`. 100ms by default.
+    pub fn write_delay(mut self, delay: Duration) -> Self {
+        self.write_delay = delay;
+        self
+    }
+
+    /// Reopen the port when it fails, see `Reconnect`. Off by default.
+    pub fn reconnect(mut self, reconnect: Reconnect) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// Wait for the controller to settle, see `WarmUp`, after
+    /// connecting and after every reconnect. Off by default.
+    pub fn warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// Drive RTS for transmit enable, see `Rts`. Off by default, for
+    /// adapters with automatic direction control.
+    pub fn rts(mut self, rts: Rts) -> Self {
+        self.rts = Some(rts);
+        self
+    }
+
+    pub async fn connect(self) -> Result<Connection> {
+        let (timeout, write_delay) = (self.request_timeout, self.write_delay);
+        let con = match self.reconnect {
+            None => self.open().await?,
+            Some(reconnect) => {
+                Connection::reconnecting(reconnect, move || {
+                    let b = self.clone();
+                    Box::pin(async move { b.open().await })
+                })
+                .await?
+            }
+        };
+        let con = con.write_delay(write_delay);
+        Ok(match timeout {
+            None => con,
+            Some(timeout) => con.request_timeout(timeout),
+        })
+    }
+
+    async fn open(&self) -> Result<Connection> {
+        let port = SerialStream::open(
+            &tokio_serial::new(&self.device, self.baud_rate)
+                .data_bits(self.data_bits)
+                .flow_control(FlowControl::None)
+                .parity(self.parity)
+                .stop_bits(self.stop_bits)
+                .timeout(self.timeout),
+        )
+        .context("failed to connect to serial port")?;
+        let port = RtsDriven::new(port, self.rts).context("failed to set RTS")?;
+        let frame_delay = self.frame_delay.unwrap_or_else(|| self.t35());
+        let (id, timeout) = (self.modbus_id, self.char_timeout);
+        let con = match self.framing {
+            Framing::Rtu => {
+                let port = Watched::new(port, timeout);
+                Connection::connect_transport(port, id, frame_delay).await?
+            }
+            Framing::Ascii => {
+                let port = Watched::new(Ascii::new(port), timeout);
+                Connection::connect_transport(port, id, frame_delay).await?
+            }
+        };
+        let con = con.share_fences(&self.device).await;
+        if let Some(warm_up) = &self.warm_up {
+            con.warm_up(warm_up).await?;
+        }
+        Ok(con)
+    }
+}
+
+/// The transport and modbus context behind one or more connections
+struct Link {
+    modbus: Modbus,
+    frame_delay: Duration,
+    idle_since: Instant,
+    wire: Arc<Wire>,
+    reopen: Option<(Reconnect, Opener)>,
+    fences: Fences,
+}
+
+impl Link {
+    async fn reopen(&mut self) -> Result<()> {
+        let (reconnect, open) = match &self.reopen {
+            Some(r) if self.wire.broken.load(Ordering::Relaxed) => r,
+            _ => return Ok(()),
+        };
+        let mut attempt = 0;
+        loop {
+            match open().await {
+                Ok(con) => {
+                    let mut link = con.link.lock().await;
+                    mem::swap(&mut self.modbus, &mut link.modbus);
+                    mem::swap(&mut self.wire, &mut link.wire);
+                    return Ok(());
+                }
+                Err(e) => {
+                    attempt += 1;
+                    if reconnect.max_attempts.map(|m| attempt >= m).unwrap_or(false) {
+                        return Err(e).context(format!(
+                            "reconnect failed after {} attempts",
+                            attempt
+                        ));
+                    }
+                    tokio::time::sleep(reconnect.delay(attempt - 1)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Exclusive use of the bus. Derefs to the modbus context and marks
+/// the bus idle when dropped.
+struct BusGuard<'a>(MutexGuard<'a, Link>);
+
+impl Deref for BusGuard<'_> {
+    type Target = Modbus;
+
+    fn deref(&self) -> &Modbus {
+        &self.0.modbus
+    }
+}
+
+impl DerefMut for BusGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Modbus {
+        &mut self.0.modbus
+    }
+}
+
+impl Drop for BusGuard<'_> {
+    fn drop(&mut self) {
+        self.0.idle_since = Instant::now();
+    }
+}
+
+/** Several controllers sharing one RS-485 bus. Each gets its own
+`Connection` handle from `connection`, and requests from all of them
+are serialized on the one port, with the frame delay observed between
+them, so tasks polling different controllers can't interleave frames.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt as ps;
+# async fn run() {
+
+let bus = ps::Bus::new("/dev/ttyUSB0").await.expect("connection failed");
+let (a, b) = (bus.connection(1), bus.connection(2));
+let (sa, sb) = futures::join!(a.stats(), b.stats());
+println!("{}\n{}", sa.expect("stats 1"), sb.expect("stats 2"));
+# }
+```
+*/
+#[derive(Clone)]
+pub struct Bus {
+    link: Arc<Mutex<Link>>,
+    // given to every handle
+    request_timeout: Option<Duration>,
+    write_delay: Duration,
+}
+
+impl Bus {
+    /// Open `device` at 9600 baud 8N2. For other port settings,
+    /// reconnection and so on, build a `Connection` and convert it with
+    /// `Bus::from`, which also passes its request timeout and write
+    /// delay on to every handle.
+    pub async fn new(device: &str) -> Result<Bus> {
+        Ok(Bus::from(Connection::new(device, 1).await?))
+    }
+
+    /// A connection to the controller at `modbus_id` on this bus. Id 0
+    /// is the broadcast address, which never answers, so a connection
+    /// to it refuses every request but `broadcast_coil`.
+    pub fn connection(&self, modbus_id: u8) -> Connection {
+        Connection {
+            link: self.link.clone(),
+            handle: NEXT_HANDLE.fetch_add(1, Ordering::Relaxed),
+            slave: AtomicU16::new(modbus_id as u16),
+            capabilities: AtomicU32::new(Capabilities::default().bits()),
+            request_timeout: self.request_timeout,
+            write_delay: self.write_delay,
+            overlay: Overlay::default(),
+            calibration: None,
+            degraded: AtomicBool::new(false),
+            single_writes: AtomicBool::new(false),
+        }
+    }
+
+    /// See `Connection::broadcast_coil`
+    pub async fn broadcast_coil(&self, coil: Coil, val: bool) -> Result<()> {
+        self.connection(0).broadcast_coil(coil, val).await
+    }
+
+    /// Try every modbus id in `ids`, e.g. `1..=247`, and report the
+    /// ones that answer within `timeout` along with their software
+    /// version. The register map doesn't identify the model, so every
+    /// controller found is assumed to be a Prostar MPPT. A few hundred
+    /// ms is plenty at 9600 baud, a full scan then takes about a
+    /// minute.
+    pub async fn scan<I: IntoIterator<Item = u8>>(
+        &self,
+        ids: I,
+        timeout: Duration,
+    ) -> Result<Vec<Found>> {
+        let mut found = Vec::new();
+        // nothing answers the broadcast address
+        for modbus_id in ids.into_iter().filter(|id| *id != 0) {
+            let con = self.connection(modbus_id).request_timeout(timeout);
+            let res = con
+                .timed(async {
+                    con.lock()
+                        .await?
+                        .read_holding_registers(0x0000, 1)
+                        .await
+                        .context("scan failed to read software version")
+                })
+                .await;
+            match res {
+                Ok(raw) if raw.len() == 1 => {
+                    found.push(Found { modbus_id, software_version: raw[0] })
+                }
+                // an odd reply, an exception, or a garbled frame are
+                // not a controller, a dead port is an error
+                Ok(_) => (),
+                Err(e) if e.is::<Timeout>() => (),
+                Err(e) => match e.downcast_ref::<io::Error>().map(|e| e.kind()) {
+                    Some(io::ErrorKind::Other) | Some(io::ErrorKind::InvalidData) => (),
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// A controller found by `Bus::scan`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Found {
+    pub modbus_id: u8,
+    pub software_version: u16,
+}
+
+impl fmt::Display for Found {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "modbus id {} software version {}",
+            self.modbus_id, self.software_version
+        )
+    }
+}
+
+/// Open `device` at 9600 baud 8N2 and `Bus::scan` it
+pub async fn scan_bus<I: IntoIterator<Item = u8>>(
+    device: &str,
+    ids: I,
+    timeout: Duration,
+) -> Result<Vec<Found>> {
+    Bus::new(device).await?.scan(ids, timeout).await
+}
+
+/// USB vendor and product ids of serial adapters commonly used with
+/// Morningstar controllers: FTDI FT232R, FT2232, FT231X, Prolific
+/// PL2303, Silicon Labs CP210x and WCH CH340
+pub const KNOWN_ADAPTERS: [(u16, u16); 6] = [
+    (0x0403, 0x6001),
+    (0x0403, 0x6010),
+    (0x0403, 0x6015),
+    (0x067B, 0x2303),
+    (0x10C4, 0xEA60),
+    (0x1A86, 0x7523),
+];
+
+/// A USB serial adapter found by `find_ports`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Port {
+    /// the serial device, e.g. /dev/ttyUSB0
+    pub device: String,
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl Port {
+    /// Scan this port for controllers, see `scan_bus`
+    pub async fn probe<I: IntoIterator<Item = u8>>(
+        &self,
+        ids: I,
+        timeout: Duration,
+    ) -> Result<Vec<Found>> {
+        scan_bus(&self.device, ids, timeout).await
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04x}:{:04x}", self.device, self.vid, self.pid)?;
+        if let Some(product) = &self.product {
+            write!(f, " {}", product)?;
+        }
+        if let Some(serial) = &self.serial_number {
+            write!(f, " ({})", serial)?;
+        }
+        Ok(())
+    }
+}
+
+/// The serial ports whose USB vendor and product id is in
+/// `KNOWN_ADAPTERS`. Use `Port::probe` to check which ones have a
+/// controller attached.
+pub fn find_ports() -> Result<Vec<Port>> {
+    let ports = tokio_serial::available_ports().context("failed to list serial ports")?;
+    Ok(ports
+        .into_iter()
+        .filter_map(|p| match p.port_type {
+            SerialPortType::UsbPort(usb)
+                if KNOWN_ADAPTERS.contains(&(usb.vid, usb.pid)) =>
+            {
+                Some(Port {
+                    device: p.port_name,
+                    vid: usb.vid,
+                    pid: usb.pid,
+                    manufacturer: usb.manufacturer,
+                    product: usb.product,
+                    serial_number: usb.serial_number,
+                })
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Share the port of a connection. Its modbus id is not used, each
+/// handle carries its own.
+impl From<Connection> for Bus {
+    fn from(con: Connection) -> Bus {
+        Bus {
+            link: con.link,
+            request_timeout: con.request_timeout,
+            write_delay: con.write_delay,
+        }
+    }
+}
+
+/** Device connection. All methods take `&self` and serialize access
+to the bus internally, so a connection can be shared between tasks
+in an `Arc`. */
+pub struct Connection {
+    link: Arc<Mutex<Link>>,
+    handle: u64,
+    // set before every request, None if unknown (from_context)
+    slave: AtomicU16,
+    capabilities: AtomicU32,
+    request_timeout: Option<Duration>,
+    write_delay: Duration,
+    overlay: Overlay,
+    calibration: Option<Calibration>,
+    degraded: AtomicBool,
+    // set once the controller has rejected write_multiple_registers
+    single_writes: AtomicBool,
+}
+
+impl Connection {
+    /// Connect at 9600 baud 8N2, use `ConnectionBuilder` for anything
+    /// else
+    pub async fn new(device: &str, modbus_id: u8) -> Result<Connection> {
+        ConnectionBuilder::new(device, modbus_id).connect().await
+    }
+
+    /// Connect through a serial to ethernet converter that tunnels raw
+    /// RTU frames over TCP, as many cheap RS-485 gateways do. This is
+    /// not MODBUS TCP, the framing is still RTU. `addr` is resolved by
+    /// tokio, so IPv4 (`192.168.1.20:4001`), IPv6 (`[2001:db8::20]:4001`)
+    /// and host names (`gateway.local:4001`) all work, and every
+    /// address a name resolves to is tried in turn.
+    pub async fn new_rtu_over_tcp<A: ToSocketAddrs>(
+        addr: A,
+        modbus_id: u8,
+    ) -> Result<Connection> {
+        let stream =
+            TcpStream::connect(addr).await.context("failed to connect to gateway")?;
+        stream.set_nodelay(true).context("failed to set TCP_NODELAY")?;
+        let peer = stream.peer_addr().context("failed to get gateway address")?;
+        let con = Connection::from_transport(stream, modbus_id).await?;
+        Ok(con.share_fences(&peer.to_string()).await)
+    }
+
+    /// Like `new_rtu_over_tcp`, but through TLS, e.g. to a gateway
+    /// behind stunnel. `config` holds the trusted roots and any client
+    /// certificate, and the gateway's certificate must be valid for
+    /// `server_name`.
+    #[cfg(feature = "tls")]
+    pub async fn new_rtu_over_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+        modbus_id: u8,
+    ) -> Result<Connection> {
+        use std::convert::TryFrom;
+        let name = rustls::pki_types::ServerName::try_from(server_name.to_string())
+            .context("invalid TLS server name")?;
+        let stream =
+            TcpStream::connect(addr).await.context("failed to connect to gateway")?;
+        stream.set_nodelay(true).context("failed to set TCP_NODELAY")?;
+        let peer = stream.peer_addr().context("failed to get gateway address")?;
+        let stream = tokio_rustls::TlsConnector::from(config)
+            .connect(name, stream)
+            .await
+            .context("TLS handshake failed")?;
+        let con = Connection::from_transport(stream, modbus_id).await?;
+        Ok(con.share_fences(&peer.to_string()).await)
+    }
+
+    /// Let processes that open the same `port`, e.g. a device path or
+    /// gateway address, see this connection's fences, see `fence`
+    async fn share_fences(self, port: &str) -> Connection {
+        self.link.lock().await.fences = Fences::shared(port);
+        self
+    }
+
+    /// Run the protocol over any byte stream, e.g. an SSH tunnel, a PTY,
+    /// or a test harness. Framing is RTU.
+    pub async fn from_transport<T>(transport: T, modbus_id: u8) -> Result<Connection>
+    where
+        T: AsyncRead + AsyncWrite + fmt::Debug + Unpin + Send + 'static,
+    {
+        let transport = Watched::new(transport, None);
+        Connection::connect_transport(transport, modbus_id, Duration::from_secs(0)).await
+    }
+
+    /// Like `from_transport`, but with MODBUS ASCII framing, e.g. for a
+    /// TCP gateway that only speaks ASCII.
+    pub async fn from_ascii_transport<T>(
+        transport: T,
+        modbus_id: u8,
+    ) -> Result<Connection>
+    where
+        T: AsyncRead + AsyncWrite + fmt::Debug + Unpin + Send + 'static,
+    {
+        let transport = Watched::new(Ascii::new(transport), None);
+        Connection::connect_transport(transport, modbus_id, Duration::from_secs(0)).await
+    }
+
+    /// Connect with `open`, and call it again to reopen the connection
+    /// whenever the transport fails, see `Reconnect`. For example
+    /// `Connection::reconnecting(Reconnect::default(), ||
+    /// Box::pin(Connection::new_rtu_over_tcp("gateway.local:4001", 1)))`.
+    /// Failures of a context passed to `from_context` can't be seen, so
+    /// such a connection is never reopened.
+    pub async fn reconnecting<F>(reconnect: Reconnect, open: F) -> Result<Connection>
+    where
+        F: Fn() -> BoxFuture<'static, Result<Connection>> + Send + Sync + 'static,
+    {
+        let con = open().await?;
+        con.link.lock().await.reopen = Some((reconnect, Box::new(open)));
+        Ok(con)
+    }
+
+    /// Wrap an already built modbus context
+    pub fn from_context(modbus: Modbus) -> Connection {
+        Connection::with_context(modbus, Duration::from_secs(0), Arc::default())
+    }
+
+    async fn connect_transport<T>(
+        transport: Watched<T>,
+        modbus_id: u8,
+        frame_delay: Duration,
+    ) -> Result<Connection>
+    where
+        T: AsyncRead + AsyncWrite + fmt::Debug + Unpin + Send + 'static,
+    {
+        let wire = transport.wire.clone();
+        let modbus = rtu::connect_slave(transport, Slave(modbus_id))
+            .await
+            .context("failed to build modbus context")?;
+        let con = Connection::with_context(modbus, frame_delay, wire);
+        con.slave.store(modbus_id as u16, Ordering::Relaxed);
+        Ok(con)
+    }
+
+    fn with_context(
+        modbus: Modbus,
+        frame_delay: Duration,
+        wire: Arc<Wire>,
+    ) -> Connection {
+        Connection {
+            link: Arc::new(Mutex::new(Link {
+                modbus,
+                frame_delay,
+                idle_since: Instant::now(),
+                wire,
+                reopen: None,
+                fences: Fences::default(),
+            })),
+            handle: NEXT_HANDLE.fetch_add(1, Ordering::Relaxed),
+            slave: AtomicU16::new(NO_SLAVE),
+            capabilities: AtomicU32::new(Capabilities::default().bits()),
+            request_timeout: None,
+            write_delay: WRITE_DELAY,
+            overlay: Overlay::default(),
+            calibration: None,
+            degraded: AtomicBool::new(false),
+            single_writes: AtomicBool::new(false),
+        }
+    }
+
+    /// Fail any request that takes longer than `timeout` once it has
+    /// the bus with a `Timeout` error, instead of hanging on a wedged
+    /// bus. Time spent waiting for other handles' requests isn't
+    /// counted. If a frame had been sent a late reply could still
+    /// arrive and be taken as the answer to the next request, so the
+    /// transport is marked failed and reopened if the connection is
+    /// `reconnecting`.
+    pub fn request_timeout(mut self, timeout: Duration) -> Connection {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// How long `write_settings` pauses before each write request,
+    /// 100ms by default. The controller can miss writes that
+    /// follow each other too closely.
+    pub fn write_delay(mut self, delay: Duration) -> Connection {
+        self.write_delay = delay;
+        self
+    }
+
+    /// Read the fields in `overlay` into `Stats::extras` along with
+    /// every stats read
+    pub fn overlay(mut self, overlay: Overlay) -> Connection {
+        self.overlay = overlay;
+        self
+    }
+
+    /// Correct the currents of every stats read with `calibration`,
+    /// see `Calibration::apply`
+    pub fn calibration(mut self, calibration: Calibration) -> Connection {
+        self.calibration = Some(calibration);
+        self
+    }
+
+    async fn timed<T, F: Future<Output = Result<T>>>(&self, f: F) -> Result<T> {
+        let timeout = match self.request_timeout {
+            None => return f.await,
+            Some(timeout) => timeout,
+        };
+        let acquired = Arc::new(std::sync::Mutex::new(None));
+        let mut f = Box::pin(ACQUIRED.scope(acquired.clone(), f));
+        let mut deadline: Option<Pin<Box<Sleep>>> = None;
+        future::poll_fn(|cx| {
+            if let Poll::Ready(r) = f.as_mut().poll(cx) {
+                return Poll::Ready(r);
+            }
+            let wire = acquired.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            if let Some(wire) = wire {
+                let d =
+                    deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                if d.as_mut().poll(cx).is_ready() {
+                    if wire.sent.load(Ordering::Relaxed) {
+                        wire.broken.store(true, Ordering::Relaxed);
+                    }
+                    return Poll::Ready(Err(Timeout(timeout).into()));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// The modbus id requests are sent to, None for a connection made
+    /// with `from_context` until `set_slave` is called
+    pub fn modbus_id(&self) -> Option<u8> {
+        match self.slave.load(Ordering::Relaxed) {
+            NO_SLAVE => None,
+            id => Some(id as u8),
+        }
+    }
+
+    /// Send subsequent requests to `modbus_id`, to talk to several
+    /// controllers in turn over one port without reopening it. The
+    /// capabilities go back to the default, since they belong to the
+    /// previous controller. To talk to them concurrently use `Bus`.
+    pub fn set_slave(&self, modbus_id: u8) {
+        self.slave.store(modbus_id as u16, Ordering::Relaxed);
+        self.capabilities.store(Capabilities::default().bits(), Ordering::Relaxed);
+    }
+
+    /// The optional register groups this connection will read and
+    /// write. All of them unless `probe_capabilities` has been called.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_bits_truncate(self.capabilities.load(Ordering::Relaxed))
+    }
+
+    /// Use `caps` instead of probing, e.g. ones recorded from an
+    /// earlier `probe_capabilities` of the same controller and firmware
+    pub fn set_capabilities(&self, caps: Capabilities) {
+        self.capabilities.store(caps.bits(), Ordering::Relaxed);
+    }
+
+    /// Take exclusive use of the bus until the guard is dropped,
+    /// reopening the transport if it failed and waiting out the frame
+    /// delay. Fails for the broadcast address, see `broadcast_coil`.
+    async fn lock(&self) -> Result<BusGuard<'_>> {
+        if self.modbus_id() == Some(0) {
+            bail!("modbus id 0 is the broadcast address, it never answers")
+        }
+        self.lock_any().await
+    }
+
+    /// `lock`, but allowing the broadcast address
+    async fn lock_any(&self) -> Result<BusGuard<'_>> {
+        let mut link = self.link.lock().await;
+        link.reopen().await?;
+        if let Some(id) = self.modbus_id() {
+            link.modbus.set_slave(Slave(id));
+        }
+        if link.frame_delay > Duration::from_secs(0) {
+            sleep_until((link.idle_since + link.frame_delay).into()).await;
+        }
+        link.wire.sent.store(false, Ordering::Relaxed);
+        let wire = link.wire.clone();
+        let _ = ACQUIRED
+            .try_with(|a| *a.lock().unwrap_or_else(|e| e.into_inner()) = Some(wire));
+        Ok(BusGuard(link))
+    }
+
+    /// Reserve `registers` of this controller for this handle, see
+    /// `fence`. Fails with `WriteFenced` if another handle already
+    /// holds an overlapping fence.
+    pub async fn fence(&self, registers: Range<u16>, holder: &str) -> Result<Fence> {
+        let modbus_id = match self.modbus_id() {
+            Some(id) => id,
+            None => bail!("can't fence registers of an unknown modbus id"),
+        };
+        let fences = self.link.lock().await.fences.clone();
+        fences.acquire(self.handle, modbus_id, registers, holder)
+    }
+
+    /// Fence all of the settings, for maintenance or while a change
+    /// is staged
+    pub async fn lease(&self, holder: &str) -> Result<Fence> {
+        self.fence(SETTINGS_BASE as u16..SETTINGS_END as u16 + 1, holder).await
+    }
+
+    /// Ask the controller which optional register groups it answers,
+    /// treating an IllegalDataAddress exception as unsupported, and
+    /// use the result for all subsequent reads and writes. Call this
+    /// right after connecting if you might be talking to older
+    /// firmware.
+    pub async fn probe_capabilities(&self) -> Result<Capabilities> {
+        let groups = [
+            (Capabilities::ARRAY_FIXED_STATS, 0x004F, 2),
+            (Capabilities::MPPT_FIXED_SETTINGS, 0xE036, 2),
+            (Capabilities::CHARGE_CURRENT_LIMIT, 0xE038, 1),
+        ];
+        let caps = self
+            .timed(async {
+                let mut modbus = self.lock().await?;
+                let mut caps = Capabilities::empty();
+                for &(cap, addr, len) in &groups {
+                    match modbus.read_holding_registers(addr, len).await {
+                        Ok(_) => caps.insert(cap),
+                        Err(e) if is_illegal_data_address(&e) => (),
+                        Err(e) => {
+                            return Err(e)
+                                .context("probe_capabilities failed to read registers")
+                        }
+                    }
+                }
+                Ok(caps)
+            })
+            .await?;
+        self.capabilities.store(caps.bits(), Ordering::Relaxed);
+        Ok(caps)
+    }
+
+    /// Wait for the controller to settle after power up or a reset,
+    /// see `WarmUp`. Call this before polling starts to avoid a burst
+    /// of bogus samples.
+    pub async fn warm_up(&self, warm_up: &WarmUp) -> Result<()> {
+        let mut last = None;
+        let settle = async {
+            let mut clean = 0;
+            loop {
+                match self.stats().await.and_then(|s| conformance::plausible(&s)) {
+                    Ok(()) => clean += 1,
+                    Err(e) => {
+                        clean = 0;
+                        last = Some(e);
+                    }
+                }
+                if clean >= warm_up.clean_reads {
+                    break;
+                }
+                tokio::time::sleep(warm_up.interval).await;
+            }
+        };
+        if tokio::time::timeout(warm_up.timeout, settle).await.is_ok() {
+            return Ok(());
+        }
+        let msg = format!("controller did not settle within {:?}", warm_up.timeout);
+        Err(match last {
+            Some(e) => e.context(msg),
+            None => anyhow!(msg),
+        })
+    }
+
+    fn stats_len(&self) -> usize {
+        if self.capabilities().contains(Capabilities::ARRAY_FIXED_STATS) {
+            STATS_LEN
+        } else {
+            0x004F
+        }
+    }
+
+    // settings are read as one contiguous block, so an unsupported
+    // group also hides any group after it
+    fn settings_len(&self) -> usize {
+        let caps = self.capabilities();
+        let end = if !caps.contains(Capabilities::MPPT_FIXED_SETTINGS) {
+            0xE035
+        } else if !caps.contains(Capabilities::CHARGE_CURRENT_LIMIT) {
+            0xE037
+        } else {
+            SETTINGS_END
+        };
+        end - SETTINGS_BASE + 1
+    }
+
+    pub async fn read_coil(&self, coil: Coil) -> Result<bool> {
+        let res = self
+            .timed(async {
+                self.lock()
+                    .await?
+                    .read_coils(coil.address(), 1)
+                    .await
+                    .context("read coil failed")
+            })
+            .await?;
         if res.len() != 1 {
             bail!("wrong number of coils read {} expected 1", res.len())
         }
         Ok(res[0])
     }
 
-    pub async fn write_coil(&mut self, coil: Coil, val: bool) -> Result<()> {
-        Ok(self
-            .0
-            .write_single_coil(coil.address(), val)
-            .await
-            .context("failed to write coil")?)
+    pub async fn write_coil(&self, coil: Coil, val: bool) -> Result<()> {
+        self.timed(async {
+            self.lock()
+                .await?
+                .write_single_coil(coil.address(), val)
+                .await
+                .context("failed to write coil")
+        })
+        .await
     }
 
-    pub async fn stats(&mut self) -> Result<Stats> {
-        let raw = self
-            .0
-            .read_holding_registers(0x0, 81)
-            .await
-            .context("stats failed to read holding registers")?;
-        if raw.len() != 81 {
-            bail!("stats wrong number of registers read {} expected 80", raw.len())
+    /// Write `coil` on every controller on the bus at once, using the
+    /// broadcast address 0, e.g. `Coil::ClearAhResettable` or
+    /// `Coil::ForceEEPROMUpdate`. Controllers don't answer broadcasts,
+    /// so success only means the request was sent. The bus is held for
+    /// a turnaround delay afterwards to give them time to act on it.
+    pub async fn broadcast_coil(&self, coil: Coil, val: bool) -> Result<()> {
+        if self.modbus_id().is_none() {
+            bail!("broadcast needs a connection that knows its modbus id")
         }
-        Ok(Stats {
-            timestamp: Local::now(),
-            software_version: raw[0x0000],
-            battery_voltage_settings_multiplier: raw[0x0001],
-            supply_3v3: v(gf32(raw[0x0004])),
-            supply_12v: v(gf32(raw[0x0005])),
-            supply_5v: v(gf32(raw[0x0006])),
-            gate_drive_voltage: v(gf32(raw[0x0007])),
-            battery_terminal_voltage: v(gf32(raw[0x0012])),
-            array_voltage: v(gf32(raw[0x0013])),
-            load_voltage: v(gf32(raw[0x0014])),
-            charge_current: a(gf32(raw[0x0010])),
-            array_current: a(gf32(raw[0x0011])),
-            load_current: a(gf32(raw[0x0016])),
-            battery_current_net: a(gf32(raw[0x0015])),
-            battery_sense_voltage: v(gf32(raw[0x0017])),
-            meterbus_voltage: v(gf32(raw[0x0008])),
-            heatsink_temperature: c(gf32(raw[0x001A])),
-            battery_temperature: c(gf32(raw[0x001B])),
-            ambient_temperature: c(gf32(raw[0x001C])),
-            rts_temperature: {
-                let t = gf32(raw[0x001D]);
-                if t.is_nan() {
-                    None
-                } else {
-                    Some(c(t))
+        self.timed(async {
+            let mut modbus = self.lock_any().await?;
+            modbus.set_slave(Slave::broadcast());
+            let write = modbus.write_single_coil(coil.address(), val);
+            match tokio::time::timeout(BROADCAST_TURNAROUND, write).await {
+                Err(_) => Ok(()),
+                Ok(r) => r.context("failed to broadcast coil"),
+            }
+        })
+        .await
+    }
+
+    /// Read a single register and return the round trip time, the
+    /// cheapest way to check the controller is there. The controller
+    /// runs from the battery, so it answers at night too, and a failed
+    /// ping means the bus or the controller is down. Marks the
+    /// connection degraded if it fails and healthy if it succeeds.
+    pub async fn ping(&self) -> Result<Duration> {
+        let start = Instant::now();
+        let res = self
+            .timed(async {
+                self.lock()
+                    .await?
+                    .read_holding_registers(0x0000, 1)
+                    .await
+                    .context("ping failed")
+            })
+            .await;
+        self.degraded.store(res.is_err(), Ordering::Relaxed);
+        res.map(|_| start.elapsed())
+    }
+
+    /// True if the last `ping` failed
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Ping `con` every `interval` in a background task, keeping
+    /// `is_degraded` current. Set a request timeout, or a ping on a
+    /// wedged bus never finishes. The task stops when every other
+    /// reference to `con` has been dropped.
+    pub fn keep_alive(con: &Arc<Connection>, interval: Duration) -> JoinHandle<()> {
+        let con = Arc::downgrade(con);
+        task::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match con.upgrade() {
+                    None => break,
+                    Some(con) => {
+                        let _ = con.ping().await;
+                    }
                 }
-            },
-            u_inductor_temperature: c(gf32(raw[0x001E])),
-            v_inductor_temperature: c(gf32(raw[0x001F])),
-            w_inductor_temperature: c(gf32(raw[0x0020])),
-            charge_state: ChargeState::from(raw[0x0021]),
-            array_faults: ArrayFaults::from_bits_truncate(raw[0x0022]),
-            battery_voltage_slow: v(gf32(raw[0x0023])),
-            target_voltage: v(gf32(raw[0x0024])),
-            ah_charge_resettable: ah(gu32(raw[0x0026], raw[0x0027]) as f32 * 0.1),
-            ah_charge_total: ah(gu32(raw[0x0028], raw[0x0029]) as f32 * 0.1),
-            kwh_charge_resettable: kwh(gf32(raw[0x002A])),
-            kwh_charge_total: kwh(gf32(raw[0x002B])),
-            load_state: LoadState::from(raw[0x002E]),
-            load_faults: LoadFaults::from_bits_truncate(raw[0x002F]),
-            lvd_setpoint: v(gf32(raw[0x0030])),
-            ah_load_resettable: ah(gu32(raw[0x0032], raw[0x0033]) as f32 * 0.1),
-            ah_load_total: ah(gu32(raw[0x0034], raw[0x0035]) as f32 * 0.1),
-            hourmeter: hr(gu32(raw[0x0036], raw[0x0037]) as f32),
-            alarms: Alarms::from_bits_truncate(
-                (raw[0x0038] as u32) << 16 | raw[0x0039] as u32,
-            ),
-            array_power: w(gf32(raw[0x003C])),
-            array_vmp: v(gf32(raw[0x003D])),
-            array_max_power_sweep: w(gf32(raw[0x003E])),
-            array_voc: v(gf32(raw[0x003F])),
-            battery_v_min_daily: v(gf32(raw[0x0041])),
-            battery_v_max_daily: v(gf32(raw[0x0042])),
-            ah_charge_daily: ah(gf32(raw[0x0043])),
-            ah_load_daily: ah(gf32(raw[0x0044])),
-            array_faults_daily: ArrayFaults::from_bits_truncate(raw[0x0045]),
-            load_faults_daily: LoadFaults::from_bits_truncate(raw[0x0046]),
-            alarms_daily: Alarms::from_bits_truncate(
-                (raw[0x0047] as u32) << 16 | raw[0x0048] as u32,
-            ),
-            array_voltage_max_daily: v(gf32(raw[0x004C])),
-            array_voltage_fixed: v(gf32(raw[0x004F])),
-            array_voc_percent_fixed: gf32(raw[0x0050]),
+            }
         })
     }
 
-    pub async fn read_settings(&mut self) -> Result<Settings> {
-        let len = ((SETTINGS_END - SETTINGS_BASE) + 1) as u16;
+    async fn read_stats_registers(&self) -> Result<Vec<u16>> {
+        let len = self.stats_len();
         let raw = self
-            .0
-            .read_holding_registers(SETTINGS_BASE as u16, len)
+            .timed(async {
+                self.lock()
+                    .await?
+                    .read_holding_registers(0x0, len as u16)
+                    .await
+                    .context("stats failed to read holding registers")
+            })
+            .await?;
+        if raw.len() != len {
+            bail!("stats wrong number of registers read {} expected {}", raw.len(), len)
+        }
+        Ok(raw)
+    }
+
+    // fields inside the stats block are taken from `raw`, the rest
+    // are read one at a time
+    async fn read_extras(&self, raw: &[u16]) -> Result<HashMap<String, FieldValue>> {
+        let mut extras = HashMap::new();
+        for def in &self.overlay.0 {
+            let (addr, len) = (def.address as usize, def.codec.registers());
+            let v = match raw.get(addr..addr + len) {
+                Some(regs) => Overlay::decode(def, regs)?,
+                None => {
+                    let regs = self
+                        .timed(async {
+                            self.lock()
+                                .await?
+                                .read_holding_registers(def.address, len as u16)
+                                .await
+                                .with_context(|| format!("failed to read {}", def.name))
+                        })
+                        .await?;
+                    Overlay::decode(def, &regs)?
+                }
+            };
+            extras.insert(def.name.clone(), v);
+        }
+        Ok(extras)
+    }
+
+    pub async fn stats(&self) -> Result<Stats> {
+        let raw = self.read_stats_registers().await?;
+        let mut stats = Stats::decode(&raw);
+        if !self.overlay.is_empty() {
+            stats.extras = self.read_extras(&raw).await?;
+        }
+        if let Some(cal) = &self.calibration {
+            cal.apply(&mut stats);
+        }
+        Ok(stats)
+    }
+
+    /// Like `stats`, but decodes into `stats` and keeps the raw
+    /// registers in `buf`, reusing its storage, for polling loops on
+    /// small machines. The response buffer allocated by the modbus
+    /// layer is the only allocation left per poll.
+    pub async fn stats_into(
+        &self,
+        stats: &mut Stats,
+        buf: &mut RegisterBuf,
+    ) -> Result<()> {
+        let raw = self.read_stats_registers().await?;
+        buf.0.clear();
+        buf.0.extend_from_slice(&raw);
+        *stats = Stats::decode(&buf.0);
+        if !self.overlay.is_empty() {
+            stats.extras = self.read_extras(&buf.0).await?;
+        }
+        if let Some(cal) = &self.calibration {
+            cal.apply(stats);
+        }
+        Ok(())
+    }
+
+    /// The controller's software version, the first stats register,
+    /// read alone so it works before capabilities are known
+    pub async fn software_version(&self) -> Result<u16> {
+        let r = self
+            .timed(async {
+                self.lock()
+                    .await?
+                    .read_holding_registers(0x0000, 1)
+                    .await
+                    .context("failed to read software version")
+            })
+            .await?;
+        match r.first() {
+            Some(v) => Ok(*v),
+            None => bail!("software version missing from reply"),
+        }
+    }
+
+    /// The controller's battery voltage settings multiplier, 1, 2 or 4
+    /// for a 12, 24 or 48 V system
+    pub async fn battery_multiplier(&self) -> Result<u16> {
+        let r = self
+            .timed(async {
+                self.lock()
+                    .await?
+                    .read_holding_registers(0x0001, 1)
+                    .await
+                    .context("failed to read battery voltage multiplier")
+            })
+            .await?;
+        match r.first() {
+            Some(m) => Ok(*m),
+            None => bail!("battery voltage multiplier missing from reply"),
+        }
+    }
+
+    /// `read_settings` with the battery voltages in system volts, see
+    /// `Settings::to_system`
+    pub async fn read_system_settings(&self) -> Result<Settings> {
+        let m = self.battery_multiplier().await?;
+        Ok(self.read_settings().await?.to_system(m))
+    }
+
+    /// `write_settings` for settings with the battery voltages in
+    /// system volts, see `Settings::to_system`. They are validated in
+    /// system volts and converted to the 12 V basis the controller
+    /// stores.
+    pub async fn write_system_settings(&self, settings: &Settings) -> Result<()> {
+        let m = self.battery_multiplier().await?;
+        settings.validate_system(m)?;
+        self.write_settings(&settings.from_system(m)).await
+    }
+
+    pub async fn read_settings(&self) -> Result<Settings> {
+        self.timed(async { self.read_settings_locked(&mut *self.lock().await?).await })
+            .await
+    }
+
+    async fn read_settings_locked(&self, modbus: &mut Modbus) -> Result<Settings> {
+        let len = self.settings_len();
+        let mut raw = modbus
+            .read_holding_registers(SETTINGS_BASE as u16, len as u16)
             .await
             .context("read_settings failed to read registers")?;
-        if raw.len() != len as usize {
+        if raw.len() != len {
             bail!(
                 "read_settings read unexpected number of registers {} expected {}",
                 raw.len(),
                 len
             );
         }
+        raw.resize(SETTINGS_END - SETTINGS_BASE + 1, 0);
         Ok(Settings {
             regulation_voltage: v(gf32(raw[0xE000 - SETTINGS_BASE])),
             float_voltage: v(gf32(raw[0xE001 - SETTINGS_BASE])),
@@ -805,142 +3129,283 @@ impl Connection {
         })
     }
 
-    async fn write_setting(&mut self, addr: usize, cur: &[u16], new: u16) -> Result<()> {
-        if cur[addr - SETTINGS_BASE] == new {
-            Ok(())
-        } else {
-            sleep(Duration::from_millis(100));
-            Ok(self
-                .0
-                .write_single_register(addr as u16, new)
+    /// Write a run of consecutive registers starting at `addr`, in one
+    /// request if the controller accepts write multiple registers,
+    /// otherwise one at a time
+    async fn write_setting_run(
+        &self,
+        modbus: &mut Modbus,
+        addr: usize,
+        vals: &[u16],
+    ) -> Result<()> {
+        if vals.len() > 1 && !self.single_writes.load(Ordering::Relaxed) {
+            tokio::time::sleep(self.write_delay).await;
+            match modbus.write_multiple_registers(addr as u16, vals).await {
+                Ok(()) => return Ok(()),
+                Err(e) if is_illegal_function(&e) => {
+                    self.single_writes.store(true, Ordering::Relaxed)
+                }
+                Err(e) => {
+                    return Err(e).context("write_setting failed to write to registers")
+                }
+            }
+        }
+        for (i, val) in vals.iter().enumerate() {
+            tokio::time::sleep(self.write_delay).await;
+            modbus
+                .write_single_register((addr + i) as u16, *val)
                 .await
-                .context("write_setting failed to write to register")?)
+                .context("write_setting failed to write to register")?;
         }
+        Ok(())
     }
 
     /// They will not take effect until the controller is reset, and
     /// if alarm_on_setting_change is false the controller will not
-    /// work until a reset.
-    pub async fn write_settings(&mut self, settings: &Settings) -> Result<()> {
+    /// work until a reset. The settings are read back after writing,
+    /// and any that didn't keep their new value are reported with a
+    /// `NotApplied` error.
+    pub async fn write_settings(&self, settings: &Settings) -> Result<()> {
+        self.timed(async {
+            self.write_settings_locked(&mut self.lock().await?, settings).await
+        })
+        .await
+    }
+
+    /// Write only the settings that differ between `old` and `new`,
+    /// skipping the read of the current settings that `write_settings`
+    /// starts with. `old` should be what the controller holds, e.g.
+    /// from an earlier `read_settings`. The written registers are read
+    /// back and checked like `write_settings` does.
+    pub async fn write_settings_delta(
+        &self,
+        old: &Settings,
+        new: &Settings,
+    ) -> Result<()> {
+        new.validate()?;
+        let end = SETTINGS_BASE + self.settings_len();
+        let mut changed = old.delta(new);
+        changed.retain(|(addr, _, _)| *addr < end);
+        self.timed(async {
+            self.write_registers_locked(&mut self.lock().await?, &changed).await
+        })
+        .await
+    }
+
+    /// Read the settings and fail with `NotApplied` if any differ from
+    /// `settings`, e.g. to check a controller after it was reset.
+    /// Settings the controller doesn't support are ignored.
+    pub async fn verify_settings(&self, settings: &Settings) -> Result<()> {
+        self.timed(async {
+            let len = self.settings_len();
+            let cur = self
+                .lock()
+                .await?
+                .read_holding_registers(SETTINGS_BASE as u16, len as u16)
+                .await
+                .context("verify_settings failed to read settings")?;
+            if cur.len() != len {
+                bail!(
+                    "verify_settings, read unexpected number of settings {} expected {}",
+                    cur.len(),
+                    len
+                )
+            }
+            NotApplied::check(settings.unapplied(&cur))
+        })
+        .await
+    }
+
+    async fn write_settings_locked(
+        &self,
+        modbus: &mut BusGuard<'_>,
+        settings: &Settings,
+    ) -> Result<()> {
         settings.validate()?;
-        let len = (SETTINGS_END - SETTINGS_BASE) as u16;
-        let cur = self
-            .0
-            .read_holding_registers(SETTINGS_BASE as u16, len)
+        let len = self.settings_len();
+        let cur = modbus
+            .read_holding_registers(SETTINGS_BASE as u16, len as u16)
             .await
             .context("write_settings failed to read current settings")?;
-        if cur.len() != len as usize {
+        if cur.len() != len {
             bail!(
                 "write_settings, read unexpected number of settings {} expected {}",
                 cur.len(),
                 len
             )
         }
-        self.write_setting(0xE000, &cur, to_v(settings.regulation_voltage)).await?;
-        self.write_setting(0xE001, &cur, to_v(settings.float_voltage)).await?;
-        self.write_setting(0xE002, &cur, to_sec(settings.time_before_float)).await?;
-        self.write_setting(0xE003, &cur, to_sec(settings.time_before_float_low_battery))
-            .await?;
-        self.write_setting(
-            0xE004,
-            &cur,
-            to_v(settings.float_low_battery_voltage_trigger),
-        )
-        .await?;
-        self.write_setting(0xE005, &cur, to_v(settings.float_cancel_voltage)).await?;
-        self.write_setting(0xE006, &cur, to_sec(settings.exit_float_time)).await?;
-        self.write_setting(0xE007, &cur, to_v(settings.equalize_voltage)).await?;
-        self.write_setting(0xE008, &cur, to_dy(settings.days_between_equalize_cycles))
-            .await?;
-        self.write_setting(
-            0xE009,
-            &cur,
-            to_sec(settings.equalize_time_limit_above_regulation_voltage),
-        )
-        .await?;
-        self.write_setting(
-            0xE00A,
-            &cur,
-            to_sec(settings.equalize_time_limit_at_regulation_voltage),
-        )
-        .await?;
-        self.write_setting(
-            0xE00D,
-            &cur,
-            if settings.alarm_on_setting_change { 1 } else { 0 },
-        )
-        .await?;
-        self.write_setting(0xE010, &cur, to_v(settings.reference_charge_voltage_limit))
-            .await?;
-        self.write_setting(0xE013, &cur, to_a(settings.battery_charge_current_limit))
-            .await?;
-        self.write_setting(
-            0xE01A,
-            &cur,
-            to_v(settings.temperature_compensation_coefficent),
-        )
-        .await?;
-        self.write_setting(0xE01B, &cur, to_v(settings.high_voltage_disconnect)).await?;
-        self.write_setting(0xE01C, &cur, to_v(settings.high_voltage_reconnect)).await?;
-        self.write_setting(0xE01D, &cur, to_v(settings.maximum_charge_voltage_reference))
-            .await?;
-        self.write_setting(
-            0xE01E,
-            &cur,
-            to_ic(settings.max_battery_temp_compensation_limit),
-        )
-        .await?;
-        self.write_setting(
-            0xE01F,
-            &cur,
-            to_ic(settings.min_battery_temp_compensation_limit),
-        )
-        .await?;
-        self.write_setting(0xE022, &cur, to_v(settings.load_low_voltage_disconnect))
-            .await?;
-        self.write_setting(0xE023, &cur, to_v(settings.load_low_voltage_reconnect))
-            .await?;
-        self.write_setting(0xE024, &cur, to_v(settings.load_high_voltage_disconnect))
-            .await?;
-        self.write_setting(0xE025, &cur, to_v(settings.load_high_voltage_reconnect))
-            .await?;
-        self.write_setting(0xE026, &cur, to_om(settings.lvd_load_current_compensation))
-            .await?;
-        self.write_setting(0xE027, &cur, to_mn(settings.lvd_warning_timeout)).await?;
-        self.write_setting(
-            0xE030,
-            &cur,
-            to_v(settings.led_green_to_green_and_yellow_limit),
-        )
-        .await?;
-        self.write_setting(
-            0xE031,
-            &cur,
-            to_v(settings.led_green_and_yellow_to_yellow_limit),
-        )
-        .await?;
-        self.write_setting(
-            0xE032,
-            &cur,
-            to_v(settings.led_yellow_to_yellow_and_red_limit),
-        )
-        .await?;
-        self.write_setting(
-            0xE033,
-            &cur,
-            to_v(settings.led_yellow_and_red_to_red_flashing_limit),
-        )
-        .await?;
-        self.write_setting(0xE034, &cur, settings.modbus_id as u16).await?;
-        self.write_setting(0xE035, &cur, settings.meterbus_id as u16).await?;
-        self.write_setting(0xE036, &cur, to_v(settings.mppt_fixed_vmp)).await?;
-        self.write_setting(
-            0xE037,
-            &cur,
-            f16::from_f32(settings.mppt_fixed_vmp_percent).to_bits(),
-        )
-        .await?;
-        self.write_setting(0xE038, &cur, to_a(settings.charge_current_limit)).await?;
-        Ok(())
+        // registers past the end of cur aren't supported by the controller
+        let changed = settings
+            .registers()
+            .iter()
+            .filter(|(addr, _, val)| {
+                cur.get(addr - SETTINGS_BASE).map(|c| c != val).unwrap_or(false)
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        self.write_registers_locked(modbus, &changed).await
+    }
+
+    /// Write `changed`, in address order, then read them back and
+    /// fail with `NotApplied` if any didn't keep their new value
+    async fn write_registers_locked(
+        &self,
+        modbus: &mut BusGuard<'_>,
+        changed: &[(usize, &'static str, u16)],
+    ) -> Result<()> {
+        let (first, last) = match (changed.first(), changed.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => return Ok(()),
+        };
+        // held until the writes are read back
+        let _shared = match self.modbus_id() {
+            None => None,
+            Some(id) => {
+                let addrs = changed.iter().map(|(addr, _, _)| *addr as u16);
+                modbus.0.fences.check(self.handle, id, addrs)?
+            }
+        };
+        let mut i = 0;
+        while i < changed.len() {
+            let start = changed[i].0;
+            let mut vals = vec![changed[i].2];
+            i += 1;
+            while i < changed.len() && changed[i].0 == start + vals.len() {
+                vals.push(changed[i].2);
+                i += 1;
+            }
+            self.write_setting_run(modbus, start, &vals).await?;
+        }
+        let cur = modbus
+            .read_holding_registers(first as u16, (last - first + 1) as u16)
+            .await
+            .context("write_settings failed to read back settings")?;
+        NotApplied::check(unapplied(changed, first, &cur))
+    }
+
+    /// The tracking mode currently programmed in the settings. The
+    /// stats registers don't say which mode is in use, so this is the
+    /// only way to find out.
+    pub async fn tracking_mode(&self) -> Result<TrackingMode> {
+        self.read_settings().await?.tracking_mode()
+    }
+
+    /// Program `mode`, leaving every other setting alone. Like
+    /// `write_settings` it takes effect after the controller is reset.
+    pub async fn set_tracking_mode(&self, mode: TrackingMode) -> Result<()> {
+        if !self.capabilities().contains(Capabilities::MPPT_FIXED_SETTINGS) {
+            bail!("controller does not support fixed tracking settings")
+        }
+        self.timed(async {
+            let mut modbus = self.lock().await?;
+            let mut settings = self.read_settings_locked(&mut modbus).await?;
+            settings.set_tracking_mode(mode)?;
+            self.write_settings_locked(&mut modbus, &settings).await
+        })
+        .await
+    }
+
+    /// Read the settings, change one with `f`, validate them and write
+    /// only the registers that changed
+    async fn set_setting<F: FnOnce(&mut Settings)>(
+        &self,
+        name: &str,
+        f: F,
+    ) -> Result<()> {
+        self.timed(async {
+            let mut modbus = self.lock().await?;
+            let old = self.read_settings_locked(&mut modbus).await?;
+            let mut new = old;
+            f(&mut new);
+            new.validate()?;
+            let changed = old.delta(&new);
+            let end = SETTINGS_BASE + self.settings_len();
+            if changed.iter().any(|(addr, _, _)| *addr >= end) {
+                bail!("controller does not support {}", name)
+            }
+            self.write_registers_locked(&mut modbus, &changed).await
+        })
+        .await
+    }
+}
+
+macro_rules! setters {
+    ($($set:ident, $field:ident: $typ:ty;)*) => {
+        impl Connection {
+            $(
+                #[doc = concat!(
+                    "Validate and write `Settings::", stringify!($field), "` alone, ",
+                    "leaving every other setting as it is. Like `write_settings` ",
+                    "it takes effect after the controller is reset."
+                )]
+                pub async fn $set(&self, v: $typ) -> Result<()> {
+                    self.set_setting(stringify!($field), |s| s.$field = v).await
+                }
+            )*
+        }
+    };
+}
+
+with_settings!(setters);
+
+impl From<ChargeState> for ChargeStage {
+    fn from(s: ChargeState) -> ChargeStage {
+        match s {
+            ChargeState::Start
+            | ChargeState::NightCheck
+            | ChargeState::Disconnect
+            | ChargeState::Night => ChargeStage::Off,
+            ChargeState::Fault => ChargeStage::Fault,
+            ChargeState::BulkMPPT => ChargeStage::Bulk,
+            ChargeState::Absorption => ChargeStage::Absorption,
+            ChargeState::Float => ChargeStage::Float,
+            ChargeState::Equalize => ChargeStage::Equalize,
+            ChargeState::Slave | ChargeState::Fixed | ChargeState::UnknownState(_) => {
+                ChargeStage::Other
+            }
+        }
+    }
+}
+
+impl ChargeController for Connection {
+    fn family(&self) -> &'static str {
+        "prostar_mppt"
+    }
+
+    fn core_stats(&self) -> BoxFuture<'_, Result<CoreStats>> {
+        Box::pin(async move {
+            let s = self.stats().await?;
+            Ok(CoreStats {
+                timestamp: s.timestamp,
+                battery_voltage: s.battery_terminal_voltage,
+                array_voltage: s.array_voltage,
+                charge_current: s.charge_current,
+                load_current: s.load_current,
+                heatsink_temperature: s.heatsink_temperature,
+                charge_stage: s.charge_state.into(),
+                fault: !s.array_faults.is_empty() || !s.load_faults.is_empty(),
+                alarm: !s.alarms.is_empty(),
+            })
+        })
+    }
+
+    fn core_settings(&self) -> BoxFuture<'_, Result<CoreSettings>> {
+        Box::pin(async move {
+            let s = self.read_settings().await?;
+            Ok(CoreSettings {
+                regulation_voltage: s.regulation_voltage,
+                float_voltage: s.float_voltage,
+                load_low_voltage_disconnect: s.load_low_voltage_disconnect,
+                load_low_voltage_reconnect: s.load_low_voltage_reconnect,
+            })
+        })
+    }
+
+    fn set_charging(&self, enabled: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.write_coil(Coil::ChargeDisconnect, !enabled))
+    }
+
+    fn set_load(&self, enabled: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.write_coil(Coil::LoadDisconnect, !enabled))
     }
 }
@@ -0,0 +1,193 @@
+/*!
+Rate of change anomaly detection over a stream of `Stats`.
+
+Threshold alarms only fire once a value is already out of range. A
+`Detector` watches how fast values move instead, which catches things
+like a failing enclosure fan (heatsink temperature climbing quickly)
+or a loose battery connection (voltage sagging quickly) while the
+values themselves still look fine.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, anomaly::*};
+use std::time::Duration;
+# async fn run() {
+
+let mut detector = Detector::new(vec![
+    Rule::new(Signal::BatteryVoltage, Direction::Falling, 0.5, Duration::from_secs(300)),
+    Rule::new(Signal::HeatsinkTemperature, Direction::Rising, 2., Duration::from_secs(600)),
+]);
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+loop {
+    let stats = con.stats().await.expect("failed to get stats");
+    for anomaly in detector.update(&stats) {
+        println!("{}", anomaly);
+    }
+}
+# }
+```
+*/
+use super::Stats;
+use chrono::prelude::*;
+use std::{collections::VecDeque, fmt, time::Duration};
+use uom::si::{
+    electric_current::ampere, electric_potential::volt,
+    thermodynamic_temperature::degree_celsius,
+};
+
+/// A value in `Stats` whose rate of change can be watched
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Signal {
+    BatteryVoltage,
+    ArrayVoltage,
+    ChargeCurrent,
+    LoadCurrent,
+    HeatsinkTemperature,
+    BatteryTemperature,
+    AmbientTemperature,
+    /// the hottest of the three inductor temperatures
+    InductorTemperature,
+}
+
+impl Signal {
+    /// The value in V, A, or °C
+    pub fn value(&self, s: &Stats) -> f32 {
+        match self {
+            Signal::BatteryVoltage => s.battery_terminal_voltage.get::<volt>(),
+            Signal::ArrayVoltage => s.array_voltage.get::<volt>(),
+            Signal::ChargeCurrent => s.charge_current.get::<ampere>(),
+            Signal::LoadCurrent => s.load_current.get::<ampere>(),
+            Signal::HeatsinkTemperature => s.heatsink_temperature.get::<degree_celsius>(),
+            Signal::BatteryTemperature => s.battery_temperature.get::<degree_celsius>(),
+            Signal::AmbientTemperature => s.ambient_temperature.get::<degree_celsius>(),
+            Signal::InductorTemperature => s
+                .u_inductor_temperature
+                .get::<degree_celsius>()
+                .max(s.v_inductor_temperature.get::<degree_celsius>())
+                .max(s.w_inductor_temperature.get::<degree_celsius>()),
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Signal::BatteryVoltage | Signal::ArrayVoltage => "V",
+            Signal::ChargeCurrent | Signal::LoadCurrent => "A",
+            Signal::HeatsinkTemperature
+            | Signal::BatteryTemperature
+            | Signal::AmbientTemperature
+            | Signal::InductorTemperature => "°C",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Direction {
+    Rising,
+    Falling,
+    Either,
+}
+
+/// Fire when `signal` moves faster than `rate` units per minute in
+/// `direction`, measured across `window`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Rule {
+    pub signal: Signal,
+    pub direction: Direction,
+    pub rate: f32,
+    pub window: Duration,
+}
+
+impl Rule {
+    pub fn new(
+        signal: Signal,
+        direction: Direction,
+        rate: f32,
+        window: Duration,
+    ) -> Rule {
+        Rule { signal, direction, rate, window }
+    }
+
+    fn violated(&self, rate: f32) -> bool {
+        match self.direction {
+            Direction::Rising => rate > self.rate,
+            Direction::Falling => -rate > self.rate,
+            Direction::Either => rate.abs() > self.rate,
+        }
+    }
+}
+
+/// A rule that started firing
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Anomaly {
+    pub timestamp: DateTime<Local>,
+    pub rule: Rule,
+    /// the measured rate in units per minute
+    pub rate: f32,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} changing at {:.2} {}/min, limit {:?} {:.2} {}/min",
+            self.timestamp,
+            self.rule.signal,
+            self.rate,
+            self.rule.signal.unit(),
+            self.rule.direction,
+            self.rule.rate,
+            self.rule.signal.unit()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    rule: Rule,
+    history: VecDeque<(DateTime<Local>, f32)>,
+    firing: bool,
+}
+
+/** Watches a stream of stats for values changing too fast */
+#[derive(Debug, Clone)]
+pub struct Detector(Vec<State>);
+
+impl Detector {
+    pub fn new(rules: Vec<Rule>) -> Detector {
+        Detector(
+            rules
+                .into_iter()
+                .map(|rule| State { rule, history: VecDeque::new(), firing: false })
+                .collect(),
+        )
+    }
+
+    /// Feed the next sample, returning the rules that started firing
+    /// with it. A rule doesn't fire again until its rate has gone
+    /// back under the limit, and nothing fires until a full window of
+    /// samples has been seen.
+    pub fn update(&mut self, s: &Stats) -> Vec<Anomaly> {
+        let mut res = Vec::new();
+        for st in self.0.iter_mut() {
+            let now = s.timestamp;
+            let age = |t: DateTime<Local>| (now - t).to_std().unwrap_or_default();
+            st.history.push_back((now, st.rule.signal.value(s)));
+            while st.history.len() > 2 && age(st.history[1].0) >= st.rule.window {
+                st.history.pop_front();
+            }
+            let (t0, v0) = st.history[0];
+            let elapsed = age(t0);
+            if elapsed < st.rule.window || elapsed == Duration::from_secs(0) {
+                continue;
+            }
+            let minutes = elapsed.as_secs_f32() / 60.;
+            let rate = (st.rule.signal.value(s) - v0) / minutes;
+            let violated = st.rule.violated(rate);
+            if violated && !st.firing {
+                res.push(Anomaly { timestamp: s.timestamp, rule: st.rule, rate });
+            }
+            st.firing = violated;
+        }
+        res
+    }
+}
@@ -0,0 +1,198 @@
+/*!
+Save and restore everything needed to replace a controller.
+
+A `DeviceArchive` bundles the settings, the state of the host
+controllable coils, and what the controller reports about itself into
+one versioned file. The file is JSON, or CBOR with the `cbor` feature
+if its name ends in `.cbor`. After a hardware failure `apply` programs
+the replacement unit from the archive of the old one.
+
+`apply` refuses with an `ArchiveMismatch` error if the replacement
+runs different firmware or scales its battery voltage settings by a
+different multiplier than the archived unit, since the settings may
+not mean the same thing on it. `apply_unchecked` writes them anyway.
+Neither restores the coils, so a replacement never starts with its
+load or charging disconnected just because the old unit's were when
+it was archived. Call `restore_coils` for that.
+
+The archive doesn't include the lighting control settings or the
+controller's logs and counters, only what `read_settings` and the
+coils cover.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, archive::DeviceArchive};
+# async fn run() {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+let archive = DeviceArchive::capture(&con).await.expect("capture failed");
+archive.save("site-a.json").expect("save failed");
+
+// later, on the replacement controller
+let archive = DeviceArchive::load("site-a.json").expect("load failed");
+archive.apply(&con).await.expect("apply failed");
+# }
+```
+*/
+use super::{Capabilities, Coil, Connection, Settings};
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+#[cfg(feature = "cbor")]
+use std::io::BufWriter;
+use std::{error, fmt, fs::File, io::BufReader, path::Path};
+
+/// The archive format version written by this version of the crate
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Coils whose state is captured. `restore_coils` only restores the
+/// disconnect coils, it won't start an equalization.
+const COILS: [Coil; 3] =
+    [Coil::EqualizeTriggered, Coil::LoadDisconnect, Coil::ChargeDisconnect];
+
+fn is_cbor(path: &Path) -> bool {
+    path.extension().map(|e| e == "cbor").unwrap_or(false)
+}
+
+/** `apply` was refused because the controller differs from the one the
+archive was captured from. Tell it apart from other failures with
+`e.is::<ArchiveMismatch>()`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveMismatch {
+    /// the archived and the target software versions
+    pub software_version: (u16, u16),
+    /// the archived and the target battery voltage settings multipliers
+    pub battery_voltage_settings_multiplier: (u16, u16),
+}
+
+impl fmt::Display for ArchiveMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (sa, st) = self.software_version;
+        let (ma, mt) = self.battery_voltage_settings_multiplier;
+        write!(
+            f,
+            "archive is from software version {} with battery voltage multiplier {}, \
+             the controller has {} and {}",
+            sa, ma, st, mt
+        )
+    }
+}
+
+impl error::Error for ArchiveMismatch {}
+
+/** The saved state of one controller */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceArchive {
+    pub version: u32,
+    pub timestamp: DateTime<Local>,
+    pub software_version: u16,
+    pub battery_voltage_settings_multiplier: u16,
+    pub capabilities: Capabilities,
+    pub settings: Settings,
+    pub coils: Vec<(Coil, bool)>,
+}
+
+impl DeviceArchive {
+    /// Read the current state of the controller
+    pub async fn capture(con: &Connection) -> Result<DeviceArchive> {
+        let stats = con.stats().await?;
+        let settings = con.read_settings().await?;
+        let mut coils = Vec::new();
+        for coil in COILS.iter() {
+            coils.push((*coil, con.read_coil(*coil).await?));
+        }
+        Ok(DeviceArchive {
+            version: ARCHIVE_VERSION,
+            timestamp: Local::now(),
+            software_version: stats.software_version,
+            battery_voltage_settings_multiplier: stats
+                .battery_voltage_settings_multiplier,
+            capabilities: con.capabilities(),
+            settings,
+            coils,
+        })
+    }
+
+    /// Save as CBOR if `path` ends in `.cbor`, otherwise JSON
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let cbor = is_cbor(path.as_ref());
+        let file = File::create(path).context("failed to create archive")?;
+        if cbor {
+            #[cfg(feature = "cbor")]
+            return ciborium::ser::into_writer(self, BufWriter::new(file))
+                .map_err(|e| anyhow!("failed to write archive: {}", e));
+            #[cfg(not(feature = "cbor"))]
+            bail!("CBOR archives need the cbor feature")
+        }
+        serde_json::to_writer_pretty(file, self).context("failed to write archive")
+    }
+
+    /// Load an archive saved by `save`, as CBOR if `path` ends in
+    /// `.cbor`, otherwise JSON
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DeviceArchive> {
+        let cbor = is_cbor(path.as_ref());
+        let file = BufReader::new(File::open(path).context("failed to open archive")?);
+        let archive: DeviceArchive = if cbor {
+            #[cfg(feature = "cbor")]
+            {
+                ciborium::de::from_reader(file)
+                    .map_err(|e| anyhow!("failed to parse archive: {}", e))?
+            }
+            #[cfg(not(feature = "cbor"))]
+            bail!("CBOR archives need the cbor feature")
+        } else {
+            serde_json::from_reader(file).context("failed to parse archive")?
+        };
+        if archive.version > ARCHIVE_VERSION {
+            bail!(
+                "archive version {} is newer than supported version {}",
+                archive.version,
+                ARCHIVE_VERSION
+            )
+        }
+        Ok(archive)
+    }
+
+    /// Program the controller with the archived settings, after
+    /// checking it runs the same software version and battery voltage
+    /// multiplier as the archived unit. Fails with `ArchiveMismatch`
+    /// if it doesn't. The coils are not restored. As with
+    /// `Connection::write_settings` the settings will not take effect
+    /// until the controller is reset.
+    pub async fn apply(&self, con: &Connection) -> Result<()> {
+        let stats = con.stats().await?;
+        let mismatch = ArchiveMismatch {
+            software_version: (self.software_version, stats.software_version),
+            battery_voltage_settings_multiplier: (
+                self.battery_voltage_settings_multiplier,
+                stats.battery_voltage_settings_multiplier,
+            ),
+        };
+        if mismatch.software_version.0 != mismatch.software_version.1
+            || mismatch.battery_voltage_settings_multiplier.0
+                != mismatch.battery_voltage_settings_multiplier.1
+        {
+            return Err(mismatch.into());
+        }
+        self.apply_unchecked(con).await
+    }
+
+    /// `apply` without checking the controller matches the archive
+    pub async fn apply_unchecked(&self, con: &Connection) -> Result<()> {
+        con.write_settings(&self.settings).await
+    }
+
+    /// Set the load and charge disconnect coils as they were when the
+    /// archive was captured. This can disconnect the load or stop
+    /// charging.
+    pub async fn restore_coils(&self, con: &Connection) -> Result<()> {
+        for (coil, val) in &self.coils {
+            match coil {
+                Coil::LoadDisconnect | Coil::ChargeDisconnect => {
+                    con.write_coil(*coil, *val).await?
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+//! MODBUS ASCII framing over an RTU modbus context.
+//!
+//! tokio-modbus only speaks RTU, so `Ascii` sits between it and the
+//! port and translates. Each RTU frame written is re-encoded as an
+//! ASCII line, ':' then the address and PDU in hex, the LRC and CRLF.
+//! Each ASCII line read is decoded back into an RTU frame with a
+//! fresh CRC. Lines with a bad LRC are dropped, so the request times
+//! out just as it would on an RTU CRC error.
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn lrc(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |a, b| a.wrapping_add(*b)).wrapping_neg()
+}
+
+fn hex(n: u8) -> Option<u8> {
+    match n {
+        b'0'..=b'9' => Some(n - b'0'),
+        b'A'..=b'F' => Some(n - b'A' + 10),
+        b'a'..=b'f' => Some(n - b'a' + 10),
+        _ => None,
+    }
+}
+
+// the RTU frame for an ASCII line without the ':' and CRLF
+fn decode_line(line: &[u8]) -> Option<Vec<u8>> {
+    if line.len() < 6 || line.len() & 1 != 0 {
+        return None;
+    }
+    let mut bin = line
+        .chunks(2)
+        .map(|c| Some(hex(c[0])? << 4 | hex(c[1])?))
+        .collect::<Option<Vec<u8>>>()?;
+    let sum = bin.pop()?;
+    if lrc(&bin) != sum {
+        return None;
+    }
+    let crc = crc16(&bin);
+    bin.extend_from_slice(&crc.to_le_bytes());
+    Some(bin)
+}
+
+// the ASCII line for an RTU frame, ignoring its CRC
+fn encode_frame(frame: &[u8]) -> Vec<u8> {
+    let frame = &frame[..frame.len().saturating_sub(2)];
+    let mut line = Vec::with_capacity(frame.len() * 2 + 5);
+    line.push(b':');
+    for b in frame.iter().chain(Some(&lrc(frame))) {
+        line.extend_from_slice(format!("{:02X}", b).as_bytes());
+    }
+    line.extend_from_slice(b"\r\n");
+    line
+}
+
+#[derive(Debug)]
+pub(super) struct Ascii<T> {
+    inner: T,
+    // the RTU frame being written, encoded on flush
+    frame: Vec<u8>,
+    out: Vec<u8>,
+    out_pos: usize,
+    line: Vec<u8>,
+    // decoded RTU bytes not yet read
+    rx: Vec<u8>,
+    rx_pos: usize,
+}
+
+impl<T> Ascii<T> {
+    pub(super) fn new(inner: T) -> Ascii<T> {
+        Ascii {
+            inner,
+            frame: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+            line: Vec::new(),
+            rx: Vec::new(),
+            rx_pos: 0,
+        }
+    }
+
+    fn take_lines(&mut self, data: &[u8]) {
+        for b in data {
+            match b {
+                b':' => self.line.clear(),
+                b'\n' => {
+                    if let Some(b'\r') = self.line.last() {
+                        self.line.pop();
+                    }
+                    if let Some(frame) = decode_line(&self.line) {
+                        self.rx.extend_from_slice(&frame);
+                    }
+                    self.line.clear();
+                }
+                b => self.line.push(*b),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Ascii<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.rx_pos < self.rx.len() {
+                let n = buf.remaining().min(self.rx.len() - self.rx_pos);
+                let pos = self.rx_pos;
+                buf.put_slice(&self.rx[pos..pos + n]);
+                self.rx_pos += n;
+                if self.rx_pos == self.rx.len() {
+                    self.rx.clear();
+                    self.rx_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            let mut tmp = [0u8; 256];
+            let mut tmp = ReadBuf::new(&mut tmp);
+            match Pin::new(&mut self.inner).poll_read(cx, &mut tmp) {
+                Poll::Ready(Ok(())) if tmp.filled().is_empty() => {
+                    return Poll::Ready(Ok(()))
+                }
+                Poll::Ready(Ok(())) => self.take_lines(tmp.filled()),
+                r => return r,
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Ascii<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.frame.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.out.is_empty() && !self.frame.is_empty() {
+            self.out = encode_frame(&self.frame);
+            self.out_pos = 0;
+            self.frame.clear();
+        }
+        while self.out_pos < self.out.len() {
+            let Ascii { inner, out, out_pos, .. } = &mut *self;
+            match Pin::new(inner).poll_write(cx, &out[*out_pos..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(n)) => *out_pos += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,128 @@
+/*!
+Software offsets for the controller's current measurements.
+
+The controller's current sensors can drift by a few hundred mA, which
+adds up over a day of amp hour counting. To calibrate, hold the
+currents steady (midday sun, a constant load), measure them with a
+clamp meter or shunt, and pass the readings to
+`Calibration::calibrate`. It averages the controller's own readings
+and stores the difference as an offset. Currents you don't have a
+reference for keep their existing offset.
+
+The controller itself is not changed. Apply the offsets to every
+`Stats` read with `Calibration::apply`, which also marks them
+`calibrated`, or attach them to a connection with
+`Connection::calibration` to have it done for you. Keep the
+calibration in a file with `save` and `load`.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, calibration::*};
+use uom::si::{electric_current::ampere, f32::ElectricCurrent};
+# async fn run() {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+let mut cal = Calibration::default();
+let reference = Reference {
+    charge_current: Some(ElectricCurrent::new::<ampere>(12.3)),
+    ..Reference::default()
+};
+cal.calibrate(&con, &reference, 10).await.expect("calibration failed");
+cal.save("calibration.json").expect("save failed");
+
+let mut stats = con.stats().await.expect("failed to get stats");
+cal.apply(&mut stats);
+println!("{}", stats);
+# }
+```
+*/
+use super::{a, Connection, Stats};
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use std::{fs::File, io::BufReader, path::Path, time::Duration};
+use tokio::time::sleep;
+use uom::si::{electric_current::ampere, f32::ElectricCurrent};
+
+/// Currents measured with an external meter while calibrating
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Reference {
+    pub charge_current: Option<ElectricCurrent>,
+    pub array_current: Option<ElectricCurrent>,
+    pub load_current: Option<ElectricCurrent>,
+}
+
+/** Offsets added to the controller's current readings */
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Calibration {
+    /// when the offsets were last updated, None if never
+    pub timestamp: Option<DateTime<Local>>,
+    pub charge_current_offset: ElectricCurrent,
+    pub array_current_offset: ElectricCurrent,
+    pub load_current_offset: ElectricCurrent,
+}
+
+impl Calibration {
+    /// Average `samples` stats one second apart and set the offset of
+    /// every current given in `reference` so that the controller's
+    /// reading matches it.
+    pub async fn calibrate(
+        &mut self,
+        con: &Connection,
+        reference: &Reference,
+        samples: usize,
+    ) -> Result<()> {
+        if samples == 0 {
+            bail!("calibrate needs at least one sample")
+        }
+        let (mut charge, mut array, mut load) = (0., 0., 0.);
+        for i in 0..samples {
+            if i > 0 {
+                sleep(Duration::from_secs(1)).await;
+            }
+            let s = con.stats().await.context("calibrate failed to read stats")?;
+            // measure against the raw readings, not ones already
+            // corrected by a calibration attached to the connection
+            let attached = con.calibration.filter(|_| s.calibrated).unwrap_or_default();
+            charge += (s.charge_current - attached.charge_current_offset).get::<ampere>();
+            array += (s.array_current - attached.array_current_offset).get::<ampere>();
+            load += (s.load_current - attached.load_current_offset).get::<ampere>();
+        }
+        let n = samples as f32;
+        if let Some(r) = reference.charge_current {
+            self.charge_current_offset = r - a(charge / n);
+        }
+        if let Some(r) = reference.array_current {
+            self.array_current_offset = r - a(array / n);
+        }
+        if let Some(r) = reference.load_current {
+            self.load_current_offset = r - a(load / n);
+        }
+        self.timestamp = Some(Local::now());
+        Ok(())
+    }
+
+    /// Correct the currents in `s` and mark it calibrated. The net
+    /// battery current is corrected by the charge and load offsets.
+    /// Stats that are already calibrated are left alone.
+    pub fn apply(&self, s: &mut Stats) {
+        if s.calibrated {
+            return;
+        }
+        s.charge_current += self.charge_current_offset;
+        s.array_current += self.array_current_offset;
+        s.load_current += self.load_current_offset;
+        s.battery_current_net += self.charge_current_offset - self.load_current_offset;
+        s.calibrated = true;
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path).context("failed to create calibration")?;
+        serde_json::to_writer_pretty(file, self).context("failed to write calibration")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Calibration> {
+        let file = File::open(path).context("failed to open calibration")?;
+        serde_json::from_reader(BufReader::new(file))
+            .context("failed to parse calibration")
+    }
+}
@@ -0,0 +1,36 @@
+/*!
+Quantization of values stored as f16.
+
+Voltages, currents and most other settings are held by the controller
+as IEEE half precision floats, which only have 11 significant bits.
+A setpoint written as 14.4 V reads back as 14.398 V, so comparing the
+value written with the value read back reports a change that didn't
+happen. Compare through `quantize` or `f16_eq` instead.
+
+# Examples
+```
+use morningstar::prostar_mppt::codec;
+
+assert_ne!(codec::quantize(14.4), 14.4);
+assert!(codec::f16_eq(14.4, codec::quantize(14.4)));
+assert!(!codec::f16_eq(14.4, 14.5));
+```
+*/
+use half::f16;
+
+/// `v` rounded to the nearest value the controller can store
+pub fn quantize(v: f32) -> f32 {
+    f16::from_f32(v).to_f32()
+}
+
+/// True if `a` and `b` would be stored as the same value
+pub fn f16_eq(a: f32, b: f32) -> bool {
+    f16::from_f32(a) == f16::from_f32(b)
+}
+
+/// The gap between `v` and the next larger storable value, the
+/// finest change the controller can represent near `v`
+pub fn resolution(v: f32) -> f32 {
+    let q = f16::from_f32(v.abs());
+    f16::from_bits(q.to_bits() + 1).to_f32() - q.to_f32()
+}
@@ -0,0 +1,488 @@
+/*!
+Compact binary encodings of `Stats` for low bandwidth links.
+
+`Stats` is encoded as a map from small integer keys to numbers, in
+CBOR (feature `cbor`) or MessagePack (feature `msgpack`). Fields that
+are zero are left out, and a decoder fills them back in as zero, so a
+controller sitting at night encodes in a few tens of bytes. The
+layout below is stable: keys will never be renumbered or reused, new
+fields only get new keys, and decoders ignore keys they don't know.
+The keys are the `ids::Field` ids.
+
+| key | field | encoding |
+|-----|-------|----------|
+| 0 | timestamp | integer, unix seconds |
+| 1 | software_version | integer |
+| 2 | battery_voltage_settings_multiplier | integer |
+| 3 | supply_3v3 | float, V |
+| 4 | supply_12v | float, V |
+| 5 | supply_5v | float, V |
+| 6 | gate_drive_voltage | float, V |
+| 7 | battery_terminal_voltage | float, V |
+| 8 | array_voltage | float, V |
+| 9 | load_voltage | float, V |
+| 10 | charge_current | float, A |
+| 11 | array_current | float, A |
+| 12 | load_current | float, A |
+| 13 | battery_current_net | float, A |
+| 14 | battery_sense_voltage | float, V |
+| 15 | meterbus_voltage | float, V |
+| 16 | heatsink_temperature | float, °C |
+| 17 | battery_temperature | float, °C |
+| 18 | ambient_temperature | float, °C |
+| 19 | rts_temperature | float, °C, absent if None |
+| 20 | u_inductor_temperature | float, °C |
+| 21 | v_inductor_temperature | float, °C |
+| 22 | w_inductor_temperature | float, °C |
+| 23 | charge_state | integer, register value |
+| 24 | array_faults | integer, bits |
+| 25 | battery_voltage_slow | float, V |
+| 26 | target_voltage | float, V |
+| 27 | ah_charge_resettable | float, Ah |
+| 28 | ah_charge_total | float, Ah |
+| 29 | kwh_charge_resettable | float, kWh |
+| 30 | kwh_charge_total | float, kWh |
+| 31 | load_state | integer, register value |
+| 32 | load_faults | integer, bits |
+| 33 | lvd_setpoint | float, V |
+| 34 | ah_load_resettable | float, Ah |
+| 35 | ah_load_total | float, Ah |
+| 36 | hourmeter | float, h |
+| 37 | alarms | integer, bits |
+| 38 | array_power | float, W |
+| 39 | array_vmp | float, V |
+| 40 | array_max_power_sweep | float, W |
+| 41 | array_voc | float, V |
+| 42 | battery_v_min_daily | float, V |
+| 43 | battery_v_max_daily | float, V |
+| 44 | ah_charge_daily | float, Ah |
+| 45 | ah_load_daily | float, Ah |
+| 46 | array_faults_daily | integer, bits |
+| 47 | load_faults_daily | integer, bits |
+| 48 | alarms_daily | integer, bits |
+| 49 | array_voltage_max_daily | float, V |
+| 50 | array_voltage_fixed | float, V |
+| 51 | array_voc_percent_fixed | float |
+| 52 | calibrated | integer, 1 if true |
+
+# Delta frames
+For links where even that is too much, `DeltaEncoder` sends a full
+keyframe every `keyframe_interval` samples and in between only the
+fields that moved further than their threshold since the last
+keyframe. Every delta is relative to the keyframe, not to the previous
+delta, so a lost delta costs nothing but that sample. A `DeltaDecoder`
+on the other end turns frames back into `Stats`. Frames use the same
+keys plus two reserved ones, 254 holding the keyframe sequence number
+and 255 holding the frame kind (0 keyframe, 1 delta). In a delta an
+absent key means unchanged, and an RTS temperature that went away is
+sent as NaN.
+*/
+use super::{
+    a, ah, c, hr, kwh, v, w, Alarms, ArrayFaults, ChargeState, LoadFaults, LoadState,
+    Stats,
+};
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use serde::{
+    de::{self, Deserializer, Visitor},
+    Deserialize, Serialize, Serializer,
+};
+use std::{collections::BTreeMap, fmt};
+use uom::si::{
+    electric_charge::ampere_hour, electric_current::ampere, electric_potential::volt,
+    energy::kilowatt_hour, power::watt, thermodynamic_temperature::degree_celsius,
+    time::hour,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Int(i64),
+    Float(f32),
+}
+
+impl Value {
+    fn is_zero(&self) -> bool {
+        match self {
+            Value::Int(i) => *i == 0,
+            Value::Float(f) => *f == 0.,
+        }
+    }
+
+    fn as_i64(&self) -> i64 {
+        match self {
+            Value::Int(i) => *i,
+            Value::Float(f) => *f as i64,
+        }
+    }
+
+    fn as_f32(&self) -> f32 {
+        match self {
+            Value::Int(i) => *i as f32,
+            Value::Float(f) => *f,
+        }
+    }
+
+    fn zero(&self) -> Value {
+        match self {
+            Value::Int(_) => Value::Int(0),
+            Value::Float(_) => Value::Float(0.),
+        }
+    }
+
+    fn moved(&self, from: &Value, threshold: f32) -> bool {
+        match (self, from) {
+            (Value::Int(i), Value::Int(j)) => i != j,
+            (Value::Float(f), Value::Float(g)) if f.is_nan() || g.is_nan() => {
+                f.is_nan() != g.is_nan()
+            }
+            (x, y) => (x.as_f32() - y.as_f32()).abs() > threshold,
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(i) => s.serialize_i64(*i),
+            Value::Float(f) => s.serialize_f32(*f),
+        }
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a number")
+    }
+
+    fn visit_i64<E: de::Error>(self, i: i64) -> Result<Value, E> {
+        Ok(Value::Int(i))
+    }
+
+    fn visit_u64<E: de::Error>(self, i: u64) -> Result<Value, E> {
+        Ok(Value::Int(i as i64))
+    }
+
+    fn visit_f32<E: de::Error>(self, f: f32) -> Result<Value, E> {
+        Ok(Value::Float(f))
+    }
+
+    fn visit_f64<E: de::Error>(self, f: f64) -> Result<Value, E> {
+        Ok(Value::Float(f as f32))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Value, D::Error> {
+        d.deserialize_any(ValueVisitor)
+    }
+}
+
+type Map = BTreeMap<u8, Value>;
+
+fn put(m: &mut Map, key: u8, val: Value) {
+    if !val.is_zero() {
+        m.insert(key, val);
+    }
+}
+
+macro_rules! quantities {
+    ($($key:expr => $field:ident: $ctor:ident($unit:ident)),* $(,)?) => {
+        fn encode_quantities(s: &Stats, m: &mut Map) {
+            $(put(m, $key, Value::Float(s.$field.get::<$unit>()));)*
+        }
+
+        fn decode_quantities(m: &Map, s: &mut Stats) {
+            $(s.$field = $ctor(m.get(&$key).map(|v| v.as_f32()).unwrap_or(0.));)*
+        }
+    };
+}
+
+quantities! {
+    3 => supply_3v3: v(volt),
+    4 => supply_12v: v(volt),
+    5 => supply_5v: v(volt),
+    6 => gate_drive_voltage: v(volt),
+    7 => battery_terminal_voltage: v(volt),
+    8 => array_voltage: v(volt),
+    9 => load_voltage: v(volt),
+    10 => charge_current: a(ampere),
+    11 => array_current: a(ampere),
+    12 => load_current: a(ampere),
+    13 => battery_current_net: a(ampere),
+    14 => battery_sense_voltage: v(volt),
+    15 => meterbus_voltage: v(volt),
+    16 => heatsink_temperature: c(degree_celsius),
+    17 => battery_temperature: c(degree_celsius),
+    18 => ambient_temperature: c(degree_celsius),
+    20 => u_inductor_temperature: c(degree_celsius),
+    21 => v_inductor_temperature: c(degree_celsius),
+    22 => w_inductor_temperature: c(degree_celsius),
+    25 => battery_voltage_slow: v(volt),
+    26 => target_voltage: v(volt),
+    27 => ah_charge_resettable: ah(ampere_hour),
+    28 => ah_charge_total: ah(ampere_hour),
+    29 => kwh_charge_resettable: kwh(kilowatt_hour),
+    30 => kwh_charge_total: kwh(kilowatt_hour),
+    33 => lvd_setpoint: v(volt),
+    34 => ah_load_resettable: ah(ampere_hour),
+    35 => ah_load_total: ah(ampere_hour),
+    36 => hourmeter: hr(hour),
+    38 => array_power: w(watt),
+    39 => array_vmp: v(volt),
+    40 => array_max_power_sweep: w(watt),
+    41 => array_voc: v(volt),
+    42 => battery_v_min_daily: v(volt),
+    43 => battery_v_max_daily: v(volt),
+    44 => ah_charge_daily: ah(ampere_hour),
+    45 => ah_load_daily: ah(ampere_hour),
+    49 => array_voltage_max_daily: v(volt),
+    50 => array_voltage_fixed: v(volt),
+}
+
+fn encode(s: &Stats) -> Map {
+    let mut m = Map::new();
+    let int = |i: u32| Value::Int(i as i64);
+    put(&mut m, 0, Value::Int(s.timestamp.timestamp()));
+    put(&mut m, 1, int(s.software_version as u32));
+    put(&mut m, 2, int(s.battery_voltage_settings_multiplier as u32));
+    if let Some(t) = s.rts_temperature {
+        m.insert(19, Value::Float(t.get::<degree_celsius>()));
+    }
+    put(&mut m, 23, int(u16::from(s.charge_state) as u32));
+    put(&mut m, 24, int(s.array_faults.bits() as u32));
+    put(&mut m, 31, int(u16::from(s.load_state) as u32));
+    put(&mut m, 32, int(s.load_faults.bits() as u32));
+    put(&mut m, 37, int(s.alarms.bits()));
+    put(&mut m, 46, int(s.array_faults_daily.bits() as u32));
+    put(&mut m, 47, int(s.load_faults_daily.bits() as u32));
+    put(&mut m, 48, int(s.alarms_daily.bits()));
+    put(&mut m, 51, Value::Float(s.array_voc_percent_fixed));
+    put(&mut m, 52, int(s.calibrated as u32));
+    encode_quantities(s, &mut m);
+    m
+}
+
+fn decode(m: &Map) -> Result<Stats> {
+    let get = |key: u8| m.get(&key).map(|v| v.as_i64()).unwrap_or(0);
+    let timestamp = match Local.timestamp_opt(get(0), 0).single() {
+        Some(ts) => ts,
+        None => bail!("invalid timestamp {}", get(0)),
+    };
+    let mut s = Stats {
+        timestamp,
+        software_version: get(1) as u16,
+        battery_voltage_settings_multiplier: get(2) as u16,
+        rts_temperature: m.get(&19).map(|t| c(t.as_f32())),
+        charge_state: ChargeState::from(get(23) as u16),
+        array_faults: ArrayFaults::from_bits_truncate(get(24) as u16),
+        load_state: LoadState::from(get(31) as u16),
+        load_faults: LoadFaults::from_bits_truncate(get(32) as u16),
+        alarms: Alarms::from_bits_truncate(get(37) as u32),
+        array_faults_daily: ArrayFaults::from_bits_truncate(get(46) as u16),
+        load_faults_daily: LoadFaults::from_bits_truncate(get(47) as u16),
+        alarms_daily: Alarms::from_bits_truncate(get(48) as u32),
+        array_voc_percent_fixed: m.get(&51).map(|v| v.as_f32()).unwrap_or(0.),
+        calibrated: get(52) != 0,
+        ..Stats::default()
+    };
+    decode_quantities(m, &mut s);
+    Ok(s)
+}
+
+#[cfg(feature = "cbor")]
+fn cbor_encode<T: Serialize>(t: &T) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(t, &mut buf).map_err(|e| anyhow!("{}", e))?;
+    Ok(buf)
+}
+
+#[cfg(feature = "cbor")]
+fn cbor_decode<T: de::DeserializeOwned>(buf: &[u8]) -> Result<T> {
+    ciborium::de::from_reader(buf).map_err(|e| anyhow!("{}", e))
+}
+
+/// Encode `s` as compact CBOR
+#[cfg(feature = "cbor")]
+pub fn to_cbor(s: &Stats) -> Result<Vec<u8>> {
+    cbor_encode(&encode(s)).context("failed to encode stats as cbor")
+}
+
+/// Decode stats encoded by `to_cbor`
+#[cfg(feature = "cbor")]
+pub fn from_cbor(buf: &[u8]) -> Result<Stats> {
+    decode(&cbor_decode(buf).context("failed to decode cbor stats")?)
+}
+
+/// Encode `s` as compact MessagePack
+#[cfg(feature = "msgpack")]
+pub fn to_msgpack(s: &Stats) -> Result<Vec<u8>> {
+    rmp_serde::to_vec(&encode(s)).context("failed to encode stats as msgpack")
+}
+
+/// Decode stats encoded by `to_msgpack`
+#[cfg(feature = "msgpack")]
+pub fn from_msgpack(buf: &[u8]) -> Result<Stats> {
+    decode(&rmp_serde::from_slice(buf).context("failed to decode msgpack stats")?)
+}
+
+const KEY_SEQ: u8 = 254;
+const KEY_KIND: u8 = 255;
+const KIND_KEYFRAME: i64 = 0;
+const KIND_DELTA: i64 = 1;
+const KEY_RTS: u8 = 19;
+
+/// A keyframe or delta produced by `DeltaEncoder`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Frame(Map);
+
+impl Frame {
+    pub fn is_keyframe(&self) -> bool {
+        self.0.get(&KEY_KIND).map(|k| k.as_i64()) == Some(KIND_KEYFRAME)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>> {
+        cbor_encode(self).context("failed to encode frame as cbor")
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(buf: &[u8]) -> Result<Frame> {
+        cbor_decode(buf).context("failed to decode cbor frame")
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub fn to_msgpack(&self) -> Result<Vec<u8>> {
+        rmp_serde::to_vec(self).context("failed to encode frame as msgpack")
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub fn from_msgpack(buf: &[u8]) -> Result<Frame> {
+        rmp_serde::from_slice(buf).context("failed to decode msgpack frame")
+    }
+}
+
+/** Turns a stream of `Stats` into keyframes and deltas */
+#[derive(Debug, Clone)]
+pub struct DeltaEncoder {
+    keyframe_interval: u32,
+    thresholds: BTreeMap<u8, f32>,
+    keyframe: Option<Map>,
+    seq: u8,
+    since_keyframe: u32,
+}
+
+impl DeltaEncoder {
+    /// Send a keyframe every `keyframe_interval` samples. Until
+    /// thresholds are set any change in a field is sent.
+    pub fn new(keyframe_interval: u32) -> DeltaEncoder {
+        DeltaEncoder {
+            keyframe_interval: keyframe_interval.max(1),
+            thresholds: BTreeMap::new(),
+            keyframe: None,
+            seq: 0,
+            since_keyframe: 0,
+        }
+    }
+
+    /// Only send the field with `key` when it has moved more than
+    /// `threshold`, in the units of the key table, since the last
+    /// keyframe. Has no effect on integer fields.
+    pub fn threshold(mut self, key: u8, threshold: f32) -> DeltaEncoder {
+        self.thresholds.insert(key, threshold);
+        self
+    }
+
+    /// Force the next frame to be a keyframe, e.g. after the link
+    /// reports a lost frame.
+    pub fn reset(&mut self) {
+        self.keyframe = None;
+    }
+
+    pub fn encode(&mut self, s: &Stats) -> Frame {
+        let cur = encode(s);
+        match &self.keyframe {
+            Some(key) if self.since_keyframe < self.keyframe_interval => {
+                self.since_keyframe += 1;
+                let mut m = Map::new();
+                for (k, v) in &cur {
+                    let from = key.get(k).copied().unwrap_or_else(|| v.zero());
+                    let threshold = self.thresholds.get(k).copied().unwrap_or(0.);
+                    if v.moved(&from, threshold) {
+                        m.insert(*k, *v);
+                    }
+                }
+                for (k, v) in key {
+                    if !cur.contains_key(k) {
+                        let to =
+                            if *k == KEY_RTS { Value::Float(f32::NAN) } else { v.zero() };
+                        let threshold = self.thresholds.get(k).copied().unwrap_or(0.);
+                        if to.moved(v, threshold) {
+                            m.insert(*k, to);
+                        }
+                    }
+                }
+                m.insert(KEY_SEQ, Value::Int(self.seq as i64));
+                m.insert(KEY_KIND, Value::Int(KIND_DELTA));
+                Frame(m)
+            }
+            _ => {
+                self.seq = self.seq.wrapping_add(1);
+                self.since_keyframe = 0;
+                self.keyframe = Some(cur.clone());
+                let mut m = cur;
+                m.insert(KEY_SEQ, Value::Int(self.seq as i64));
+                m.insert(KEY_KIND, Value::Int(KIND_KEYFRAME));
+                Frame(m)
+            }
+        }
+    }
+}
+
+/** Turns frames from a `DeltaEncoder` back into `Stats` */
+#[derive(Debug, Clone, Default)]
+pub struct DeltaDecoder {
+    keyframe: Option<(i64, Map)>,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> DeltaDecoder {
+        DeltaDecoder::default()
+    }
+
+    /// Decode a frame. A delta whose keyframe was never received is
+    /// an error, the sample can't be reconstructed until the next
+    /// keyframe arrives.
+    pub fn decode(&mut self, frame: &Frame) -> Result<Stats> {
+        let mut m = frame.0.clone();
+        let seq = match m.remove(&KEY_SEQ) {
+            Some(seq) => seq.as_i64(),
+            None => bail!("frame has no sequence number"),
+        };
+        match m.remove(&KEY_KIND).map(|k| k.as_i64()) {
+            Some(KIND_KEYFRAME) => {
+                self.keyframe = Some((seq, m.clone()));
+                decode(&m)
+            }
+            Some(KIND_DELTA) => match &self.keyframe {
+                Some((kseq, key)) if *kseq == seq => {
+                    let mut full = key.clone();
+                    for (k, v) in m {
+                        if k == KEY_RTS && v.as_f32().is_nan() {
+                            full.remove(&k);
+                        } else {
+                            full.insert(k, v);
+                        }
+                    }
+                    decode(&full)
+                }
+                _ => bail!("missing keyframe {} for delta", seq),
+            },
+            kind => bail!("unknown frame kind {:?}", kind),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+/*!
+What temperature compensation does to the charge voltages.
+
+The controller lowers its regulation (absorption) and float voltages
+by `temperature_compensation_coefficent` volts per °C above 25°C and
+raises them below it. The battery temperature is clamped to the
+compensation limits first, and the result is capped at
+`maximum_charge_voltage_reference`. `preview` shows the effective
+voltages at a few temperatures for the current and proposed settings
+side by side, so a change to any of these can be sanity checked
+before it is written.
+
+Like the settings themselves, the voltages are for a 12 V battery.
+Multiply by `Stats::battery_voltage_settings_multiplier` for 24 V and
+48 V systems.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, compensation};
+# async fn run(proposed: ps::Settings) {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+let current = con.read_settings().await.expect("failed to read settings");
+println!("{}", compensation::preview(&current, &proposed));
+# }
+```
+*/
+use super::Settings;
+use std::fmt;
+use uom::si::{
+    electric_potential::volt,
+    f32::{ElectricPotential, ThermodynamicTemperature},
+    thermodynamic_temperature::degree_celsius,
+};
+
+/// The battery temperatures shown by `preview`, in °C
+pub const PREVIEW_TEMPERATURES: [f32; 4] = [-20., 0., 25., 40.];
+
+/// The charge voltages the controller targets at one battery
+/// temperature
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Compensated {
+    pub temperature: ThermodynamicTemperature,
+    pub regulation_voltage: ElectricPotential,
+    pub float_voltage: ElectricPotential,
+}
+
+/// The effective charge voltages of `settings` at `temperature`
+pub fn compensated(
+    settings: &Settings,
+    temperature: ThermodynamicTemperature,
+) -> Compensated {
+    let t = temperature.get::<degree_celsius>();
+    let min = settings.min_battery_temp_compensation_limit.get::<degree_celsius>();
+    let max = settings.max_battery_temp_compensation_limit.get::<degree_celsius>();
+    let t_eff = if min <= max { t.max(min).min(max) } else { t };
+    let delta =
+        settings.temperature_compensation_coefficent.get::<volt>() * (25. - t_eff);
+    let cap = settings.maximum_charge_voltage_reference.get::<volt>();
+    let adjust = |v: ElectricPotential| {
+        let v = v.get::<volt>() + delta;
+        ElectricPotential::new::<volt>(if cap > 0. { v.min(cap) } else { v })
+    };
+    Compensated {
+        temperature,
+        regulation_voltage: adjust(settings.regulation_voltage),
+        float_voltage: adjust(settings.float_voltage),
+    }
+}
+
+/** Effective charge voltages of two sets of settings at
+`PREVIEW_TEMPERATURES` */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preview {
+    /// (current, proposed) at each temperature
+    pub rows: Vec<(Compensated, Compensated)>,
+}
+
+pub fn preview(current: &Settings, proposed: &Settings) -> Preview {
+    let rows = PREVIEW_TEMPERATURES
+        .iter()
+        .map(|t| {
+            let t = ThermodynamicTemperature::new::<degree_celsius>(*t);
+            (compensated(current, t), compensated(proposed, t))
+        })
+        .collect();
+    Preview { rows }
+}
+
+impl fmt::Display for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>8} | {:^16} | {:^16}", "", "regulation V", "float V")?;
+        writeln!(
+            f,
+            "{:>8} | {:>6}    {:<6} | {:>6}    {:<6}",
+            "", "now", "new", "now", "new"
+        )?;
+        for (cur, new) in &self.rows {
+            writeln!(
+                f,
+                "{:>6.0}°C | {:>6.2} -> {:<6.2} | {:>6.2} -> {:<6.2}",
+                cur.temperature.get::<degree_celsius>(),
+                cur.regulation_voltage.get::<volt>(),
+                new.regulation_voltage.get::<volt>(),
+                cur.float_voltage.get::<volt>(),
+                new.float_voltage.get::<volt>(),
+            )?;
+        }
+        Ok(())
+    }
+}
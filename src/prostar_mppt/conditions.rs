@@ -0,0 +1,561 @@
+/*!
+Human readable descriptions of fault and alarm conditions.
+
+`conditions` iterates over every fault and alarm flag set in a
+`Stats`, one `Condition` per flag. Each condition has a stable `key`,
+a `severity` for triage, and an English `description` and
+recommended `action`. The descriptions follow the flag names in
+Morningstar's MODBUS specification, but the severities and actions are
+this crate's own suggestions, not Morningstar's; check the controller
+manual before acting on them. To show descriptions in another
+language, enable the `translations` feature and implement `Catalog` or
+load a `Translation` from a JSON map of keys to text. Anything missing
+from the translation falls back to English.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, conditions::*};
+# async fn run() {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+let stats = con.stats().await.expect("failed to get stats");
+for c in conditions(&stats).filter(|c| c.severity() >= Severity::Warning) {
+    println!("{:?} {}: {}", c.severity(), c.description(), c.action());
+}
+# }
+```
+*/
+use super::{Alarms, ArrayFaults, LoadFaults, Stats};
+#[cfg(feature = "translations")]
+use anyhow::{Context, Result};
+use std::fmt;
+#[cfg(feature = "translations")]
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+use Severity::{Critical, Info, Warning};
+
+/// The flag, its name in keys, its severity, description and
+/// recommended action
+type Entry<T> = (T, &'static str, Severity, &'static str, &'static str);
+
+const ARRAY_FAULTS: [Entry<ArrayFaults>; 16] = [
+    (
+        ArrayFaults::OVER_CURRENT,
+        "OVER_CURRENT",
+        Critical,
+        "Array over-current",
+        "Check the array is within the controller's rated current and look for shorted \
+        wiring.",
+    ),
+    (
+        ArrayFaults::MOSFET_SHORTED,
+        "MOSFET_SHORTED",
+        Critical,
+        "Charge MOSFET shorted",
+        "Disconnect the array and replace the controller.",
+    ),
+    (
+        ArrayFaults::SOFTWARE,
+        "SOFTWARE",
+        Critical,
+        "Charge control software fault",
+        "Reset the controller. If it persists the controller needs service.",
+    ),
+    (
+        ArrayFaults::BATTERY_HVD,
+        "BATTERY_HVD",
+        Warning,
+        "Battery high voltage disconnect",
+        "Check for other charging sources and that the voltage settings match the \
+        battery.",
+    ),
+    (
+        ArrayFaults::ARRAY_HVD,
+        "ARRAY_HVD",
+        Critical,
+        "Array high voltage disconnect",
+        "Array voltage is over the controller's limit. Reduce the number of modules in \
+        series.",
+    ),
+    (
+        ArrayFaults::CUSTOM_SETTINGS_EDIT,
+        "CUSTOM_SETTINGS_EDIT",
+        Info,
+        "Custom settings were edited",
+        "Reset the controller to apply the new settings.",
+    ),
+    (
+        ArrayFaults::RTS_SHORTED,
+        "RTS_SHORTED",
+        Warning,
+        "Remote temperature sensor shorted",
+        "Check the remote temperature sensor wiring or replace the sensor.",
+    ),
+    (
+        ArrayFaults::RTS_NO_LONGER_VALID,
+        "RTS_NO_LONGER_VALID",
+        Warning,
+        "Remote temperature sensor disconnected",
+        "Reconnect the remote temperature sensor, or reset the controller to run without \
+        it.",
+    ),
+    (
+        ArrayFaults::LOCAL_TEMP_SENSOR_DAMAGED,
+        "LOCAL_TEMP_SENSOR_DAMAGED",
+        Critical,
+        "Local temperature sensor damaged",
+        "The controller needs service.",
+    ),
+    (
+        ArrayFaults::BATTERY_LOW_VOLTAGE_DISCONNECT,
+        "BATTERY_LOW_VOLTAGE_DISCONNECT",
+        Warning,
+        "Battery low voltage disconnect",
+        "Recharge the battery and check the voltage settings.",
+    ),
+    (
+        ArrayFaults::SLAVE_TIMEOUT,
+        "SLAVE_TIMEOUT",
+        Warning,
+        "Timed out waiting for the master controller",
+        "Check the communication link to the master controller.",
+    ),
+    (
+        ArrayFaults::DIP_SWITCH_CHANGED,
+        "DIP_SWITCH_CHANGED",
+        Info,
+        "DIP switches changed",
+        "Reset the controller to apply the new DIP switch settings.",
+    ),
+    (
+        ArrayFaults::FAULT13,
+        "FAULT13",
+        Warning,
+        "Reserved array fault 13",
+        "Undocumented fault. Reset the controller. If it persists the controller needs \
+        service.",
+    ),
+    (
+        ArrayFaults::FAULT14,
+        "FAULT14",
+        Warning,
+        "Reserved array fault 14",
+        "Undocumented fault. Reset the controller. If it persists the controller needs \
+        service.",
+    ),
+    (
+        ArrayFaults::FAULT15,
+        "FAULT15",
+        Warning,
+        "Reserved array fault 15",
+        "Undocumented fault. Reset the controller. If it persists the controller needs \
+        service.",
+    ),
+    (
+        ArrayFaults::FAULT16,
+        "FAULT16",
+        Warning,
+        "Reserved array fault 16",
+        "Undocumented fault. Reset the controller. If it persists the controller needs \
+        service.",
+    ),
+];
+
+const LOAD_FAULTS: [Entry<LoadFaults>; 8] = [
+    (
+        LoadFaults::EXTERNAL_SHORT_CIRCIT,
+        "EXTERNAL_SHORT_CIRCIT",
+        Critical,
+        "Load short circuit",
+        "Find and fix the short in the load wiring, then clear faults.",
+    ),
+    (
+        LoadFaults::OVERCURRENT,
+        "OVERCURRENT",
+        Warning,
+        "Load over-current",
+        "Reduce the load below the controller's rated load current, then clear faults.",
+    ),
+    (
+        LoadFaults::MOSFET_SHORTED,
+        "MOSFET_SHORTED",
+        Critical,
+        "Load MOSFET shorted",
+        "The load can't be disconnected. Replace the controller.",
+    ),
+    (
+        LoadFaults::SOFTWARE,
+        "SOFTWARE",
+        Critical,
+        "Load control software fault",
+        "Reset the controller. If it persists the controller needs service.",
+    ),
+    (
+        LoadFaults::LOAD_HVD,
+        "LOAD_HVD",
+        Warning,
+        "Load high voltage disconnect",
+        "Check the battery voltage and other charging sources.",
+    ),
+    (
+        LoadFaults::HIGH_TEMP_DISCONNECT,
+        "HIGH_TEMP_DISCONNECT",
+        Warning,
+        "Load disconnected, controller too hot",
+        "Improve ventilation around the controller and reduce the load.",
+    ),
+    (
+        LoadFaults::DIP_SWITCH_CHANGED,
+        "DIP_SWITCH_CHANGED",
+        Info,
+        "DIP switches changed",
+        "Reset the controller to apply the new DIP switch settings.",
+    ),
+    (
+        LoadFaults::CUSTOM_SETTINGS_EDIT,
+        "CUSTOM_SETTINGS_EDIT",
+        Info,
+        "Custom settings were edited",
+        "Reset the controller to apply the new settings.",
+    ),
+];
+
+const ALARMS: [Entry<Alarms>; 27] = [
+    (
+        Alarms::RTS_OPEN,
+        "RTS_OPEN",
+        Warning,
+        "Remote temperature sensor open",
+        "Check the remote temperature sensor wiring.",
+    ),
+    (
+        Alarms::RTS_SHORTED,
+        "RTS_SHORTED",
+        Warning,
+        "Remote temperature sensor shorted",
+        "Check the remote temperature sensor wiring or replace the sensor.",
+    ),
+    (
+        Alarms::RTS_DISCONNECTED,
+        "RTS_DISCONNECTED",
+        Warning,
+        "Remote temperature sensor disconnected",
+        "Reconnect the remote temperature sensor.",
+    ),
+    (
+        Alarms::HEATSINK_TEMP_SENSOR_OPEN,
+        "HEATSINK_TEMP_SENSOR_OPEN",
+        Critical,
+        "Heatsink temperature sensor open",
+        "The controller needs service.",
+    ),
+    (
+        Alarms::HEATSINK_TEMP_SENSOR_SHORTED,
+        "HEATSINK_TEMP_SENSOR_SHORTED",
+        Critical,
+        "Heatsink temperature sensor shorted",
+        "The controller needs service.",
+    ),
+    (
+        Alarms::HEATSINK_TEMP_LIMIT,
+        "HEATSINK_TEMP_LIMIT",
+        Warning,
+        "Heatsink temperature limit reached",
+        "Improve ventilation around the controller. Charging is being derated.",
+    ),
+    (
+        Alarms::INDUCTOR_TEMP_SENSOR_OPEN,
+        "INDUCTOR_TEMP_SENSOR_OPEN",
+        Critical,
+        "Inductor temperature sensor open",
+        "The controller needs service.",
+    ),
+    (
+        Alarms::INDUCTOR_TEMP_SENSOR_SHORTED,
+        "INDUCTOR_TEMP_SENSOR_SHORTED",
+        Critical,
+        "Inductor temperature sensor shorted",
+        "The controller needs service.",
+    ),
+    (
+        Alarms::INDUCTOR_TEMP_LIMIT,
+        "INDUCTOR_TEMP_LIMIT",
+        Warning,
+        "Inductor temperature limit reached",
+        "Improve ventilation around the controller. Charging is being derated.",
+    ),
+    (
+        Alarms::CURRENT_LIMIT,
+        "CURRENT_LIMIT",
+        Info,
+        "Charge current limited",
+        "None unless it persists with a cool controller; check the current limit \
+        settings.",
+    ),
+    (
+        Alarms::CURRENT_MEASUREMENT_ERROR,
+        "CURRENT_MEASUREMENT_ERROR",
+        Warning,
+        "Current measurement error",
+        "Reset the controller. If it persists the controller needs service.",
+    ),
+    (
+        Alarms::BATTERY_SENSE_OUT_OF_RANGE,
+        "BATTERY_SENSE_OUT_OF_RANGE",
+        Warning,
+        "Battery sense voltage out of range",
+        "Check the battery sense wires are connected to the right battery.",
+    ),
+    (
+        Alarms::BATTERY_SENSE_DISCONNECTED,
+        "BATTERY_SENSE_DISCONNECTED",
+        Warning,
+        "Battery sense wires disconnected",
+        "Reconnect the battery sense wires, or ignore if battery sense isn't used.",
+    ),
+    (
+        Alarms::UNCALIBRATED,
+        "UNCALIBRATED",
+        Critical,
+        "Controller is uncalibrated",
+        "The controller needs service.",
+    ),
+    (
+        Alarms::TB5V,
+        "TB5V",
+        Warning,
+        "Terminal block 5 V supply fault",
+        "Check the wiring of accessories powered from the terminal block.",
+    ),
+    (
+        Alarms::FP10SUPPLY_OUT_OF_RANGE,
+        "FP10SUPPLY_OUT_OF_RANGE",
+        Warning,
+        "FP10 supply out of range",
+        "Reset the controller. If it persists the controller needs service.",
+    ),
+    (Alarms::UNUSED, "UNUSED", Info, "Unused alarm bit", "None."),
+    (
+        Alarms::MOSFET_OPEN,
+        "MOSFET_OPEN",
+        Critical,
+        "Charge MOSFET open",
+        "The controller can't charge. Replace the controller.",
+    ),
+    (
+        Alarms::ARRAY_CURRENT_OFFSET,
+        "ARRAY_CURRENT_OFFSET",
+        Warning,
+        "Array current offset out of range",
+        "Reset the controller at night. If it persists the controller needs service.",
+    ),
+    (
+        Alarms::LOAD_CURRENT_OFFSET,
+        "LOAD_CURRENT_OFFSET",
+        Warning,
+        "Load current offset out of range",
+        "Reset the controller with the load off. If it persists the controller needs \
+        service.",
+    ),
+    (
+        Alarms::P3V3_SUPPLY_OUT_OF_RANGE,
+        "P3V3_SUPPLY_OUT_OF_RANGE",
+        Warning,
+        "3.3 V supply out of range",
+        "Reset the controller. If it persists the controller needs service.",
+    ),
+    (
+        Alarms::P12V_SUPPLY_OUT_OF_RANGE,
+        "P12V_SUPPLY_OUT_OF_RANGE",
+        Warning,
+        "12 V supply out of range",
+        "Reset the controller. If it persists the controller needs service.",
+    ),
+    (
+        Alarms::HIGH_INPUT_VOLTAGE_LIMIT,
+        "HIGH_INPUT_VOLTAGE_LIMIT",
+        Warning,
+        "Array input voltage limit reached",
+        "Check the array's cold weather open circuit voltage against the controller's \
+        limit.",
+    ),
+    (
+        Alarms::CONTROLLER_RESET,
+        "CONTROLLER_RESET",
+        Info,
+        "Controller was reset",
+        "None unless the reset was unexpected.",
+    ),
+    (
+        Alarms::LOAD_LVD,
+        "LOAD_LVD",
+        Warning,
+        "Load low voltage disconnect",
+        "Recharge the battery or reduce the load.",
+    ),
+    (Alarms::LOG_TIMEOUT, "LOG_TIMEOUT", Info, "Log timeout", "None."),
+    (
+        Alarms::EEPROM_ACCESS_FAILURE,
+        "EEPROM_ACCESS_FAILURE",
+        Critical,
+        "EEPROM access failure",
+        "Reset the controller. If it persists the controller needs service.",
+    ),
+];
+
+/// How urgently a condition needs attention
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// informational, no action needed
+    Info,
+    /// degraded operation, needs attention soon
+    Warning,
+    /// charging or the load has stopped, or the controller is damaged
+    Critical,
+}
+
+/// A single fault or alarm flag
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Condition {
+    ArrayFault(ArrayFaults),
+    LoadFault(LoadFaults),
+    Alarm(Alarms),
+}
+
+impl Condition {
+    /// A stable key naming the flag, e.g. "alarm.HEATSINK_TEMP_LIMIT",
+    /// used to look up translations. Combined flags name each flag
+    /// set, separated by " | ".
+    pub fn key(&self) -> String {
+        fn names<T>(table: &[Entry<T>], set: impl Fn(&T) -> bool) -> String {
+            let names = table.iter().filter(|e| set(&e.0)).map(|e| e.1);
+            names.collect::<Vec<_>>().join(" | ")
+        }
+        match self {
+            Condition::ArrayFault(f) => {
+                format!("array_fault.{}", names(&ARRAY_FAULTS, |e| f.contains(*e)))
+            }
+            Condition::LoadFault(f) => {
+                format!("load_fault.{}", names(&LOAD_FAULTS, |e| f.contains(*e)))
+            }
+            Condition::Alarm(a) => {
+                format!("alarm.{}", names(&ALARMS, |e| a.contains(*e)))
+            }
+        }
+    }
+
+    fn entry(&self) -> Option<(Severity, &'static str, &'static str)> {
+        fn find<T: PartialEq>(
+            table: &[Entry<T>],
+            t: &T,
+        ) -> Option<(Severity, &'static str, &'static str)> {
+            table.iter().find(|e| e.0 == *t).map(|e| (e.2, e.3, e.4))
+        }
+        match self {
+            Condition::ArrayFault(f) => find(&ARRAY_FAULTS, f),
+            Condition::LoadFault(f) => find(&LOAD_FAULTS, f),
+            Condition::Alarm(a) => find(&ALARMS, a),
+        }
+    }
+
+    /// How urgent the condition is. Combined flags are `Warning`.
+    pub fn severity(&self) -> Severity {
+        self.entry().map(|e| e.0).unwrap_or(Severity::Warning)
+    }
+
+    /// The English description
+    pub fn description(&self) -> &'static str {
+        self.entry().map(|e| e.1).unwrap_or("Unknown condition")
+    }
+
+    /// The recommended action, in English. A suggestion of this
+    /// crate's, not Morningstar's.
+    pub fn action(&self) -> &'static str {
+        self.entry().map(|e| e.2).unwrap_or("Consult the controller manual.")
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/// Every fault and alarm flag currently set in `s`
+pub fn conditions(s: &Stats) -> impl Iterator<Item = Condition> + '_ {
+    let array = ARRAY_FAULTS
+        .iter()
+        .filter(move |e| s.array_faults.contains(e.0))
+        .map(|e| Condition::ArrayFault(e.0));
+    let load = LOAD_FAULTS
+        .iter()
+        .filter(move |e| s.load_faults.contains(e.0))
+        .map(|e| Condition::LoadFault(e.0));
+    let alarms = ALARMS
+        .iter()
+        .filter(move |e| s.alarms.contains(e.0))
+        .map(|e| Condition::Alarm(e.0));
+    array.chain(load).chain(alarms)
+}
+
+/// A source of condition descriptions in some language
+#[cfg(feature = "translations")]
+pub trait Catalog {
+    /// The description of `c`, or None to fall back to English
+    fn lookup(&self, c: Condition) -> Option<&str>;
+
+    /// The recommended action for `c`, or None to fall back to English
+    fn lookup_action(&self, _c: Condition) -> Option<&str> {
+        None
+    }
+
+    fn describe(&self, c: Condition) -> &str {
+        self.lookup(c).unwrap_or_else(|| c.description())
+    }
+
+    fn action(&self, c: Condition) -> &str {
+        self.lookup_action(c).unwrap_or_else(|| c.action())
+    }
+}
+
+/** Descriptions loaded from a JSON object mapping `Condition::key`s
+to text, and `Condition::key` followed by ".action" to the
+recommended action
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, conditions::*};
+# async fn run() {
+
+let es = Translation::load("es.json").expect("failed to load translation");
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+let stats = con.stats().await.expect("failed to get stats");
+for c in conditions(&stats) {
+    println!("{}: {}", es.describe(c), es.action(c));
+}
+# }
+```
+*/
+#[cfg(feature = "translations")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Translation(pub HashMap<String, String>);
+
+#[cfg(feature = "translations")]
+impl Translation {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Translation> {
+        let file = File::open(path).context("failed to open translation")?;
+        serde_json::from_reader(BufReader::new(file))
+            .context("failed to parse translation")
+    }
+}
+
+#[cfg(feature = "translations")]
+impl Catalog for Translation {
+    fn lookup(&self, c: Condition) -> Option<&str> {
+        self.0.get(&c.key()).map(|s| s.as_str())
+    }
+
+    fn lookup_action(&self, c: Condition) -> Option<&str> {
+        self.0.get(&format!("{}.action", c.key())).map(|s| s.as_str())
+    }
+}
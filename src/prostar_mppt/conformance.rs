@@ -0,0 +1,217 @@
+/*!
+Read only conformance checks against a live controller.
+
+`run` reads the stats and settings blocks, checks that they respond,
+that every f16 register decodes to a finite number, and that the
+decoded values are physically plausible for a Prostar MPPT. Nothing is
+written to the controller, so it is safe to point at a unit that is in
+service, e.g. to check a new firmware or RS-485 gateway before
+deploying.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, conformance};
+# async fn run() {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+let report = conformance::run(&con).await;
+println!("{}", report);
+assert!(report.passed());
+# }
+```
+*/
+use super::{Connection, Stats};
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use half::f16;
+use std::fmt;
+use tokio_modbus::prelude::*;
+use uom::si::{
+    electric_current::ampere, electric_potential::volt,
+    thermodynamic_temperature::degree_celsius, Unit,
+};
+
+/// Stats registers holding f16 values. The RTS temperature (0x001D)
+/// is left out because it is legitimately NaN when no RTS is fitted.
+const F16_STATS_REGISTERS: &[usize] = &[
+    0x0004, 0x0005, 0x0006, 0x0007, 0x0008, 0x0010, 0x0011, 0x0012, 0x0013, 0x0014,
+    0x0015, 0x0016, 0x0017, 0x001A, 0x001B, 0x001C, 0x001E, 0x001F, 0x0020, 0x0023,
+    0x0024, 0x002A, 0x002B, 0x0030, 0x003C, 0x003D, 0x003E, 0x003F, 0x0041, 0x0042,
+    0x0043, 0x0044, 0x004C, 0x004F, 0x0050,
+];
+
+/// The outcome of a single check.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub detail: Option<String>,
+}
+
+/** The result of a conformance run */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub timestamp: DateTime<Local>,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// true if every check passed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+
+    fn check(&mut self, name: &str, res: Result<()>) {
+        let (passed, detail) = match res {
+            Ok(()) => (true, None),
+            Err(e) => (false, Some(format!("{:#}", e))),
+        };
+        self.checks.push(Check { name: name.into(), passed, detail });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Report {{")?;
+        writeln!(f, "    timestamp: {},", self.timestamp)?;
+        for c in &self.checks {
+            match &c.detail {
+                None => writeln!(f, "    {}: ok,", c.name)?,
+                Some(d) => writeln!(f, "    {}: FAILED {},", c.name, d)?,
+            }
+        }
+        write!(f, "}}")?;
+        Ok(())
+    }
+}
+
+fn range(name: &str, x: f32, min: f32, max: f32, unit: &str) -> Result<()> {
+    if x.is_nan() || x < min || x > max {
+        bail!("{} {:.2} {} outside {} <= x <= {}", name, x, unit, min, max)
+    }
+    Ok(())
+}
+
+macro_rules! plausible {
+    ($s:ident, $field:ident, $unit:ident, $min:expr, $max:expr) => {
+        range(
+            stringify!($field),
+            $s.$field.get::<$unit>(),
+            $min,
+            $max,
+            $unit::abbreviation(),
+        )
+    };
+}
+
+fn check_f16(raw: &[u16]) -> Result<()> {
+    let bad = F16_STATS_REGISTERS
+        .iter()
+        .filter(|i| **i < raw.len() && !f16::from_bits(raw[**i]).is_finite())
+        .map(|i| format!("{:#06X}", i))
+        .collect::<Vec<_>>();
+    if !bad.is_empty() {
+        bail!("registers {} are not finite", bad.join(", "))
+    }
+    Ok(())
+}
+
+fn check_multiplier(s: &Stats) -> Result<()> {
+    match s.battery_voltage_settings_multiplier {
+        1 | 2 | 4 => Ok(()),
+        m => bail!("battery_voltage_settings_multiplier {} not in 1, 2, 4", m),
+    }
+}
+
+fn check_supplies(s: &Stats) -> Result<()> {
+    plausible!(s, supply_3v3, volt, 3.0, 3.6)?;
+    plausible!(s, supply_5v, volt, 4.5, 5.5)?;
+    plausible!(s, supply_12v, volt, 10.8, 13.2)?;
+    Ok(())
+}
+
+fn check_voltages(s: &Stats) -> Result<()> {
+    let max = 17.5 * s.battery_voltage_settings_multiplier.max(1) as f32;
+    plausible!(s, battery_terminal_voltage, volt, 0., max)?;
+    plausible!(s, battery_sense_voltage, volt, 0., max)?;
+    plausible!(s, battery_voltage_slow, volt, 0., max)?;
+    plausible!(s, array_voltage, volt, 0., 120.)?;
+    plausible!(s, array_voc, volt, 0., 120.)?;
+    Ok(())
+}
+
+fn check_currents(s: &Stats) -> Result<()> {
+    plausible!(s, charge_current, ampere, -1., 45.)?;
+    plausible!(s, array_current, ampere, -1., 45.)?;
+    plausible!(s, load_current, ampere, -1., 45.)?;
+    Ok(())
+}
+
+fn check_temperatures(s: &Stats) -> Result<()> {
+    plausible!(s, heatsink_temperature, degree_celsius, -40., 100.)?;
+    plausible!(s, battery_temperature, degree_celsius, -40., 100.)?;
+    plausible!(s, ambient_temperature, degree_celsius, -40., 100.)?;
+    plausible!(s, u_inductor_temperature, degree_celsius, -40., 150.)?;
+    plausible!(s, v_inductor_temperature, degree_celsius, -40., 150.)?;
+    plausible!(s, w_inductor_temperature, degree_celsius, -40., 150.)?;
+    Ok(())
+}
+
+/// Check that every value in `s` is physically plausible, the stats
+/// checks of `run` without reading the controller
+pub fn plausible(s: &Stats) -> Result<()> {
+    check_multiplier(s)?;
+    check_supplies(s)?;
+    check_voltages(s)?;
+    check_currents(s)?;
+    check_temperatures(s)
+}
+
+async fn read_raw_stats(con: &Connection) -> Result<Vec<u16>> {
+    let len = con.stats_len();
+    let raw = con
+        .timed(async {
+            con.lock()
+                .await?
+                .read_holding_registers(0x0, len as u16)
+                .await
+                .context("failed to read holding registers")
+        })
+        .await?;
+    if raw.len() != len {
+        bail!("wrong number of registers read {} expected {}", raw.len(), len)
+    }
+    Ok(raw)
+}
+
+/// Run every check against the controller and report the results. A
+/// check that can't be run because a read failed is reported as
+/// failed rather than aborting the run.
+pub async fn run(con: &Connection) -> Report {
+    let mut report = Report { timestamp: Local::now(), checks: Vec::new() };
+    match read_raw_stats(con).await {
+        Err(e) => report.check("stats registers respond", Err(e)),
+        Ok(raw) => {
+            report.check("stats registers respond", Ok(()));
+            report.check("stats f16 values finite", check_f16(&raw));
+        }
+    }
+    match con.stats().await {
+        Err(e) => report.check("stats decode", Err(e)),
+        Ok(s) => {
+            report.check("settings multiplier", check_multiplier(&s));
+            report.check("supply rails plausible", check_supplies(&s));
+            report.check("voltages plausible", check_voltages(&s));
+            report.check("currents plausible", check_currents(&s));
+            report.check("temperatures plausible", check_temperatures(&s));
+        }
+    }
+    match con.read_settings().await {
+        Err(e) => report.check("settings registers respond", Err(e)),
+        Ok(s) => {
+            report.check("settings registers respond", Ok(()));
+            report.check("settings in range", s.validate());
+        }
+    }
+    report
+}
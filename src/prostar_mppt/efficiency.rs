@@ -0,0 +1,211 @@
+/*!
+Long run charge efficiency from the controller's energy counters.
+
+The controller counts the energy and charge it delivers to the
+battery, and the charge drawn through its load output, but not the
+energy taken from the array. An `Efficiency` integrates the sampled
+array power to fill that in, and over a sliding window (a few days is
+reasonable) reports two ratios:
+
+- conversion, battery kWh over array kWh, how much of the harvest
+  reaches the battery.
+- load ratio, load Ah over charge Ah. On a system whose loads are all
+  on the load output and whose battery is cycling normally this stays
+  roughly constant. When it falls the battery is taking more charge
+  for the same use, which suggests an aging battery or a parasitic
+  load that bypasses the controller.
+
+Either can be given a lower limit, and `update` reports when a ratio
+falls below it. The controller's kWh counter is a half float, which
+at a few hundred kWh only counts in steps of 0.25 to 0.5 kWh, so the
+battery energy is instead taken from the 0.1 Ah resolution charge
+counter times the sampled battery voltage. Both energies are thus
+integrated from samples, and the conversion ratio is only as good as
+the polling interval is short compared to how fast the array power and
+battery voltage change.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, efficiency::Efficiency};
+use std::time::Duration;
+# async fn run() {
+
+let mut eff = Efficiency::new(Duration::from_secs(3 * 86400));
+eff.min_load_ratio = Some(0.8);
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+loop {
+    let stats = con.stats().await.expect("failed to get stats");
+    for d in eff.update(&stats) {
+        println!("{}", d);
+    }
+    tokio::time::sleep(Duration::from_secs(60)).await;
+}
+# }
+```
+*/
+use super::Stats;
+use chrono::prelude::*;
+use std::{collections::VecDeque, fmt, time::Duration};
+use uom::si::{electric_charge::ampere_hour, electric_potential::volt, power::watt};
+
+/// Least array energy in kWh over which conversion is reported
+const MIN_ARRAY_KWH: f64 = 0.05;
+
+/// Least charge in Ah over which the load ratio is reported
+const MIN_CHARGE_AH: f64 = 1.;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    timestamp: DateTime<Local>,
+    array_power: f64,
+    battery_voltage: f64,
+    // integrated since the first sample ever seen
+    array_kwh: f64,
+    battery_kwh: f64,
+    charge_ah: f64,
+    load_ah: f64,
+}
+
+/// One of the ratios tracked by `Efficiency`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Figure {
+    /// battery kWh over array kWh
+    Conversion,
+    /// load Ah over charge Ah
+    LoadRatio,
+}
+
+/// A ratio that fell below its limit
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Degraded {
+    pub timestamp: DateTime<Local>,
+    pub figure: Figure,
+    pub value: f32,
+    pub limit: f32,
+}
+
+impl fmt::Display for Degraded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} fell to {:.1}%, limit {:.1}%",
+            self.timestamp,
+            self.figure,
+            self.value * 100.,
+            self.limit * 100.
+        )
+    }
+}
+
+/** Charge efficiency over a sliding window of `Stats` */
+#[derive(Debug, Clone)]
+pub struct Efficiency {
+    window: Duration,
+    history: VecDeque<Sample>,
+    firing: [bool; 2],
+    /// report when the conversion ratio falls below this
+    pub min_conversion: Option<f32>,
+    /// report when the load ratio falls below this
+    pub min_load_ratio: Option<f32>,
+}
+
+impl Efficiency {
+    pub fn new(window: Duration) -> Efficiency {
+        Efficiency {
+            window,
+            history: VecDeque::new(),
+            firing: [false; 2],
+            min_conversion: None,
+            min_load_ratio: None,
+        }
+    }
+
+    /// Feed the next sample, returning the figures that fell below
+    /// their limits with it. A figure isn't reported again until it has
+    /// recovered, and nothing is reported until a full window has been
+    /// seen. If a counter goes backwards, because it was reset or the
+    /// controller was replaced, the history starts over.
+    pub fn update(&mut self, s: &Stats) -> Vec<Degraded> {
+        let mut next = Sample {
+            timestamp: s.timestamp,
+            array_power: s.array_power.get::<watt>() as f64,
+            battery_voltage: s.battery_voltage_slow.get::<volt>() as f64,
+            array_kwh: 0.,
+            battery_kwh: 0.,
+            charge_ah: s.ah_charge_total.get::<ampere_hour>() as f64,
+            load_ah: s.ah_load_total.get::<ampere_hour>() as f64,
+        };
+        if let Some(last) = self.history.back() {
+            let hours =
+                (next.timestamp - last.timestamp).num_milliseconds() as f64 / 3.6e6;
+            if hours < 0.
+                || next.charge_ah < last.charge_ah
+                || next.load_ah < last.load_ah
+            {
+                self.history.clear();
+            } else {
+                let mean = (last.array_power + next.array_power) / 2.;
+                next.array_kwh = last.array_kwh + mean * hours / 1000.;
+                let volts = (last.battery_voltage + next.battery_voltage) / 2.;
+                let ah = next.charge_ah - last.charge_ah;
+                next.battery_kwh = last.battery_kwh + ah * volts / 1000.;
+            }
+        }
+        self.history.push_back(next);
+        let now = next.timestamp;
+        let age = |t: DateTime<Local>| (now - t).to_std().unwrap_or_default();
+        while self.history.len() > 2 && age(self.history[1].timestamp) >= self.window {
+            self.history.pop_front();
+        }
+        let mut res = Vec::new();
+        if age(self.history[0].timestamp) < self.window {
+            return res;
+        }
+        let figures = [
+            (Figure::Conversion, self.conversion(), self.min_conversion),
+            (Figure::LoadRatio, self.load_ratio(), self.min_load_ratio),
+        ];
+        for (firing, (figure, value, limit)) in self.firing.iter_mut().zip(figures.iter())
+        {
+            let (value, limit) = match (value, limit) {
+                (Some(value), Some(limit)) => (*value, *limit),
+                _ => continue,
+            };
+            let violated = value < limit;
+            if violated && !*firing {
+                res.push(Degraded { timestamp: now, figure: *figure, value, limit });
+            }
+            *firing = violated;
+        }
+        res
+    }
+
+    fn span(&self) -> Option<(&Sample, &Sample)> {
+        Some((self.history.front()?, self.history.back()?))
+    }
+
+    /// Battery kWh over array kWh across the window, None until
+    /// enough energy has been harvested to tell. The battery kWh are
+    /// charge Ah times the battery voltage, see the module docs.
+    pub fn conversion(&self) -> Option<f32> {
+        let (first, last) = self.span()?;
+        let array = last.array_kwh - first.array_kwh;
+        if array < MIN_ARRAY_KWH {
+            None
+        } else {
+            Some(((last.battery_kwh - first.battery_kwh) / array) as f32)
+        }
+    }
+
+    /// Load Ah over charge Ah across the window, None until enough
+    /// charge has gone in to tell.
+    pub fn load_ratio(&self) -> Option<f32> {
+        let (first, last) = self.span()?;
+        let charge = last.charge_ah - first.charge_ah;
+        if charge < MIN_CHARGE_AH {
+            None
+        } else {
+            Some(((last.load_ah - first.load_ah) / charge) as f32)
+        }
+    }
+}
@@ -0,0 +1,267 @@
+/*!
+Advisory write fences between connections sharing a port.
+
+When several handles share one port, e.g. from a `Bus`, nothing stops
+one operator's settings change from being overwritten by another's
+half way through. A `Fence`, taken with `Connection::fence` or
+`Connection::lease`, reserves a range of registers on one controller
+for the handle that took it. While it is held, `write_settings` from
+any other handle that would change one of those registers fails with
+a `WriteFenced` error, before anything is written. Dropping the fence
+releases it.
+
+Handles on a serial port opened by device name, or on a gateway opened
+with `new_rtu_over_tcp` or `new_rtu_over_tls`, also see fences held by other processes on the
+same machine. The first fence a process takes on a controller holds an
+exclusive `flock` style lock on a file in the temporary directory named
+after the port and modbus id, and every fenced write run holds a shared
+lock on it. On unix the file is only readable and writable by the user
+who created it, and is never opened through a symlink, so processes of
+another user sharing the port fail to fence or write instead. Between processes a fence covers the whole controller, not
+just its registers. Connections made with `from_transport` or
+`from_context` have no name to lock, so their fences are only seen in
+the same process.
+
+Fences are advisory. They don't stop reads, writes from programs that
+don't use them, or writes to coils.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, fence::WriteFenced};
+# async fn run() {
+
+let bus = ps::Bus::new("/dev/ttyUSB0").await.expect("connection failed");
+let (alice, bob) = (bus.connection(1), bus.connection(1));
+let lease = alice.lease("alice").await.expect("controller already leased");
+let settings = bob.read_settings().await.expect("failed to read settings");
+match bob.write_settings(&settings).await {
+    Err(e) if e.is::<WriteFenced>() => println!("{}", e),
+    r => r.expect("failed to write settings"),
+}
+drop(lease);
+# }
+```
+*/
+use anyhow::{Context, Result};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    env, error, fmt,
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+static NEXT_KEY: AtomicU64 = AtomicU64::new(0);
+
+/** A write was refused because another handle holds a fence over
+registers it would change. Tell it apart from other failures with
+`e.is::<WriteFenced>()` or `e.downcast_ref::<WriteFenced>()`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteFenced {
+    pub modbus_id: u8,
+    /// the fenced registers
+    pub registers: Range<u16>,
+    /// who holds the fence
+    pub holder: String,
+}
+
+impl fmt::Display for WriteFenced {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "registers {:#06X}..{:#06X} of modbus id {} are fenced by {}",
+            self.registers.start, self.registers.end, self.modbus_id, self.holder
+        )
+    }
+}
+
+impl error::Error for WriteFenced {}
+
+/// The exclusive lock on a controller's fence file, cleared before it
+/// is released so the holder recorded in it is never stale
+#[derive(Debug)]
+struct Held(File);
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        let _ = self.0.set_len(0);
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: u64,
+    handle: u64,
+    fenced: WriteFenced,
+    held: Option<Arc<Held>>,
+}
+
+/// The fences held on one port
+#[derive(Debug, Clone, Default)]
+pub(super) struct Fences {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    // names the fence files shared with other processes
+    port: Option<Arc<str>>,
+}
+
+impl Fences {
+    /// Fences also seen by other processes using `port`, e.g. a
+    /// device path or gateway address
+    pub(super) fn shared(port: &str) -> Fences {
+        Fences { entries: Arc::default(), port: Some(port.into()) }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Vec<Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn open(&self, modbus_id: u8) -> Result<Option<File>> {
+        let port = match &self.port {
+            None => return Ok(None),
+            Some(port) => port,
+        };
+        let name = port
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        let path: PathBuf = env::temp_dir()
+            .join(format!("morningstar-fence-{}-{}.lock", name, modbus_id));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+        let file = options
+            .open(&path)
+            .with_context(|| format!("failed to open fence file {}", path.display()))?;
+        Ok(Some(file))
+    }
+
+    /// The fence another process holds on `modbus_id`, as recorded in
+    /// its fence file
+    fn held_elsewhere(file: &mut File, modbus_id: u8) -> WriteFenced {
+        let mut buf = String::new();
+        let _ = file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut buf));
+        let mut parts = buf.splitn(3, ' ');
+        let start = parts.next().and_then(|s| s.parse().ok());
+        let end = parts.next().and_then(|s| s.parse().ok());
+        match (start, end, parts.next()) {
+            (Some(start), Some(end), Some(holder)) => {
+                WriteFenced { modbus_id, registers: start..end, holder: holder.into() }
+            }
+            // locked by a write run rather than a fence
+            _ => WriteFenced {
+                modbus_id,
+                registers: 0..u16::MAX,
+                holder: "a write in another process".into(),
+            },
+        }
+    }
+
+    /// Fence `registers` of `modbus_id` for `handle`, unless another
+    /// handle or process already holds an overlapping fence, in which
+    /// case the error is a `WriteFenced`
+    pub(super) fn acquire(
+        &self,
+        handle: u64,
+        modbus_id: u8,
+        registers: Range<u16>,
+        holder: &str,
+    ) -> Result<Fence> {
+        let mut entries = self.entries();
+        let conflict = entries.iter().find(|e| {
+            e.handle != handle
+                && e.fenced.modbus_id == modbus_id
+                && e.fenced.registers.start < registers.end
+                && registers.start < e.fenced.registers.end
+        });
+        if let Some(e) = conflict {
+            return Err(e.fenced.clone().into());
+        }
+        let held = match entries.iter().find(|e| e.fenced.modbus_id == modbus_id) {
+            Some(e) => e.held.clone(),
+            None => match self.open(modbus_id)? {
+                None => None,
+                Some(mut file) => match file.try_lock() {
+                    Ok(()) => {
+                        let held = Held(file);
+                        let rec =
+                            format!("{} {} {}", registers.start, registers.end, holder);
+                        // a holder that was killed never cleared its record
+                        held.0
+                            .set_len(0)
+                            .and_then(|()| (&held.0).write_all(rec.as_bytes()))
+                            .context("failed to write fence file")?;
+                        Some(Arc::new(held))
+                    }
+                    Err(TryLockError::WouldBlock) => {
+                        return Err(Fences::held_elsewhere(&mut file, modbus_id).into())
+                    }
+                    Err(TryLockError::Error(e)) => {
+                        return Err(e).context("failed to lock fence file")
+                    }
+                },
+            },
+        };
+        let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+        let fenced = WriteFenced { modbus_id, registers, holder: holder.into() };
+        entries.push(Entry { key, handle, fenced, held });
+        Ok(Fence { fences: self.clone(), key })
+    }
+
+    /// Fail if `handle` may not write to any of `addrs` on `modbus_id`.
+    /// Unless this process holds a fence on `modbus_id`, the returned
+    /// lock keeps other processes from fencing it until the write is
+    /// done.
+    pub(super) fn check<I: IntoIterator<Item = u16>>(
+        &self,
+        handle: u64,
+        modbus_id: u8,
+        addrs: I,
+    ) -> Result<Option<File>> {
+        let entries = self.entries();
+        for addr in addrs {
+            let fenced = entries.iter().find(|e| {
+                e.handle != handle
+                    && e.fenced.modbus_id == modbus_id
+                    && e.fenced.registers.contains(&addr)
+            });
+            if let Some(e) = fenced {
+                return Err(e.fenced.clone().into());
+            }
+        }
+        if entries.iter().any(|e| e.fenced.modbus_id == modbus_id) {
+            return Ok(None);
+        }
+        match self.open(modbus_id)? {
+            None => Ok(None),
+            Some(mut file) => match file.try_lock_shared() {
+                Ok(()) => Ok(Some(file)),
+                Err(TryLockError::WouldBlock) => {
+                    Err(Fences::held_elsewhere(&mut file, modbus_id).into())
+                }
+                Err(TryLockError::Error(e)) => {
+                    Err(e).context("failed to lock fence file")
+                }
+            },
+        }
+    }
+}
+
+/** A fence held by one handle, released when dropped */
+#[derive(Debug)]
+pub struct Fence {
+    fences: Fences,
+    key: u64,
+}
+
+impl Drop for Fence {
+    fn drop(&mut self) {
+        self.fences.entries().retain(|e| e.key != self.key)
+    }
+}
@@ -0,0 +1,150 @@
+/*!
+Stable numeric ids for `Stats` fields and fault and alarm conditions.
+
+The ids never change meaning between crate versions. New fields get
+new ids, and removed fields leave their id unused. This makes them
+safe to use as point ids in SCADA, DNP3 or SunSpec mappings. Field
+ids are the same numbers the compact encoding uses as map keys.
+
+Condition ids are derived from the flag's bit position in the
+controller register: array faults are 1000 + bit, load faults
+1100 + bit, and alarms 1200 + bit.
+
+# Examples
+```
+use morningstar::prostar_mppt::ids::Field;
+
+assert_eq!(Field::BatteryTerminalVoltage.id(), 7);
+assert_eq!(Field::from_id(7), Some(Field::BatteryTerminalVoltage));
+assert_eq!(Field::BatteryTerminalVoltage.name(), "battery_terminal_voltage");
+```
+*/
+use super::{conditions::Condition, Alarms, ArrayFaults, LoadFaults};
+
+macro_rules! fields {
+    ($($id:expr => $variant:ident $name:ident,)*) => {
+        /// A field of `Stats`
+        #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+        pub enum Field {
+            $($variant,)*
+        }
+
+        impl Field {
+            /// Every field, in id order
+            pub const ALL: &'static [Field] = &[$(Field::$variant,)*];
+
+            pub fn id(&self) -> u16 {
+                match self {
+                    $(Field::$variant => $id,)*
+                }
+            }
+
+            pub fn from_id(id: u16) -> Option<Field> {
+                match id {
+                    $($id => Some(Field::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// The name of the field in `Stats`
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Field::$variant => stringify!($name),)*
+                }
+            }
+        }
+    };
+}
+
+fields! {
+    0 => Timestamp timestamp,
+    1 => SoftwareVersion software_version,
+    2 => BatteryVoltageSettingsMultiplier battery_voltage_settings_multiplier,
+    3 => Supply3v3 supply_3v3,
+    4 => Supply12v supply_12v,
+    5 => Supply5v supply_5v,
+    6 => GateDriveVoltage gate_drive_voltage,
+    7 => BatteryTerminalVoltage battery_terminal_voltage,
+    8 => ArrayVoltage array_voltage,
+    9 => LoadVoltage load_voltage,
+    10 => ChargeCurrent charge_current,
+    11 => ArrayCurrent array_current,
+    12 => LoadCurrent load_current,
+    13 => BatteryCurrentNet battery_current_net,
+    14 => BatterySenseVoltage battery_sense_voltage,
+    15 => MeterbusVoltage meterbus_voltage,
+    16 => HeatsinkTemperature heatsink_temperature,
+    17 => BatteryTemperature battery_temperature,
+    18 => AmbientTemperature ambient_temperature,
+    19 => RtsTemperature rts_temperature,
+    20 => UInductorTemperature u_inductor_temperature,
+    21 => VInductorTemperature v_inductor_temperature,
+    22 => WInductorTemperature w_inductor_temperature,
+    23 => ChargeState charge_state,
+    24 => ArrayFaults array_faults,
+    25 => BatteryVoltageSlow battery_voltage_slow,
+    26 => TargetVoltage target_voltage,
+    27 => AhChargeResettable ah_charge_resettable,
+    28 => AhChargeTotal ah_charge_total,
+    29 => KwhChargeResettable kwh_charge_resettable,
+    30 => KwhChargeTotal kwh_charge_total,
+    31 => LoadState load_state,
+    32 => LoadFaults load_faults,
+    33 => LvdSetpoint lvd_setpoint,
+    34 => AhLoadResettable ah_load_resettable,
+    35 => AhLoadTotal ah_load_total,
+    36 => Hourmeter hourmeter,
+    37 => Alarms alarms,
+    38 => ArrayPower array_power,
+    39 => ArrayVmp array_vmp,
+    40 => ArrayMaxPowerSweep array_max_power_sweep,
+    41 => ArrayVoc array_voc,
+    42 => BatteryVMinDaily battery_v_min_daily,
+    43 => BatteryVMaxDaily battery_v_max_daily,
+    44 => AhChargeDaily ah_charge_daily,
+    45 => AhLoadDaily ah_load_daily,
+    46 => ArrayFaultsDaily array_faults_daily,
+    47 => LoadFaultsDaily load_faults_daily,
+    48 => AlarmsDaily alarms_daily,
+    49 => ArrayVoltageMaxDaily array_voltage_max_daily,
+    50 => ArrayVoltageFixed array_voltage_fixed,
+    51 => ArrayVocPercentFixed array_voc_percent_fixed,
+    52 => Calibrated calibrated,
+}
+
+const ARRAY_FAULT_BASE: u16 = 1000;
+const LOAD_FAULT_BASE: u16 = 1100;
+const ALARM_BASE: u16 = 1200;
+
+impl Condition {
+    /// The stable id of this condition. Only meaningful for a single
+    /// flag, as produced by `conditions::conditions`.
+    pub fn id(&self) -> u16 {
+        match self {
+            Condition::ArrayFault(f) => {
+                ARRAY_FAULT_BASE + f.bits().trailing_zeros() as u16
+            }
+            Condition::LoadFault(f) => LOAD_FAULT_BASE + f.bits().trailing_zeros() as u16,
+            Condition::Alarm(a) => ALARM_BASE + a.bits().trailing_zeros() as u16,
+        }
+    }
+
+    pub fn from_id(id: u16) -> Option<Condition> {
+        let bit = |base: u16, n: u16| {
+            if id >= base && id < base + n {
+                Some(id - base)
+            } else {
+                None
+            }
+        };
+        if let Some(b) = bit(ARRAY_FAULT_BASE, 16) {
+            ArrayFaults::from_bits(1 << b).map(Condition::ArrayFault)
+        } else if let Some(b) = bit(LOAD_FAULT_BASE, 16) {
+            LoadFaults::from_bits(1 << b).map(Condition::LoadFault)
+        } else if let Some(b) = bit(ALARM_BASE, 32) {
+            Alarms::from_bits(1 << b).map(Condition::Alarm)
+        } else {
+            None
+        }
+    }
+}
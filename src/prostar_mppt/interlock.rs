@@ -0,0 +1,141 @@
+/*!
+A battery temperature interlock on charging, for lithium banks.
+
+Lithium cells must not be charged below freezing or above about
+45°C, and the controller itself will charge at any temperature. An
+`Interlock` sits between your code and `Coil::ChargeDisconnect`:
+`set_charging` refuses (or warns about) turning charging back on while
+the battery temperature is outside the limits, and `enforce`, called
+with every stats read, disconnects charging when the temperature
+leaves the limits or an alarm shows it can't be trusted.
+
+The interlock lives on the host. It does nothing while your program
+isn't running, so it is a second line of defense behind the BMS, not
+a replacement for it.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, interlock::Interlock};
+# async fn run() {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+let settings = con.read_settings().await.expect("failed to read settings");
+let interlock = Interlock::from_settings(&settings);
+loop {
+    let stats = con.stats().await.expect("failed to get stats");
+    if let Some(why) = interlock.enforce(&con, &stats).await.expect("enforce failed") {
+        println!("charging disconnected: {}", why);
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+}
+# }
+```
+*/
+use super::{Alarms, Coil, Connection, Settings, Stats};
+use anyhow::Result;
+use uom::si::{f32::ThermodynamicTemperature, thermodynamic_temperature::degree_celsius};
+
+/// What `Interlock::set_charging` does when asked to enable charging
+/// outside the limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Policy {
+    /// don't enable charging, return an error
+    Refuse,
+    /// enable charging anyway, return a warning
+    Warn,
+}
+
+/** Temperature limits and alarms that gate charging */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Interlock {
+    pub min_temperature: ThermodynamicTemperature,
+    pub max_temperature: ThermodynamicTemperature,
+    pub policy: Policy,
+    /// `enforce` disconnects charging when any of these is raised
+    pub disconnect_alarms: Alarms,
+    /// `enforce` disconnects charging when the battery temperature is
+    /// outside the limits
+    pub disconnect_outside_limits: bool,
+}
+
+impl Interlock {
+    /// Use the controller's temperature compensation limits, refuse
+    /// to enable charging outside them, and disconnect when they are
+    /// exceeded or the remote temperature sensor fails.
+    pub fn from_settings(settings: &Settings) -> Interlock {
+        Interlock {
+            min_temperature: settings.min_battery_temp_compensation_limit,
+            max_temperature: settings.max_battery_temp_compensation_limit,
+            policy: Policy::Refuse,
+            disconnect_alarms: Alarms::RTS_OPEN
+                | Alarms::RTS_SHORTED
+                | Alarms::RTS_DISCONNECTED,
+            disconnect_outside_limits: true,
+        }
+    }
+
+    fn outside_limits(&self, s: &Stats) -> Option<String> {
+        let t = s.battery_temperature;
+        if t.is_nan() || t < self.min_temperature || t > self.max_temperature {
+            Some(format!(
+                "battery temperature {:.1}°C outside {}°C to {}°C",
+                t.get::<degree_celsius>(),
+                self.min_temperature.get::<degree_celsius>(),
+                self.max_temperature.get::<degree_celsius>()
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn alarmed(&self, s: &Stats) -> Option<String> {
+        let raised = s.alarms & self.disconnect_alarms;
+        if raised.is_empty() {
+            None
+        } else {
+            Some(format!("alarms {:?}", raised))
+        }
+    }
+
+    /// Why charging shouldn't be enabled given `s`, or None if it can
+    pub fn check(&self, s: &Stats) -> Option<String> {
+        self.alarmed(s).or_else(|| self.outside_limits(s))
+    }
+
+    /// Enable or disable charging. Disabling always goes through.
+    /// Enabling reads the stats first, and if `check` objects either
+    /// fails or, under `Policy::Warn`, enables anyway and returns the
+    /// objection.
+    pub async fn set_charging(
+        &self,
+        con: &Connection,
+        enabled: bool,
+    ) -> Result<Option<String>> {
+        let mut warning = None;
+        if enabled {
+            if let Some(why) = self.check(&con.stats().await?) {
+                match self.policy {
+                    Policy::Refuse => bail!("refusing to enable charging, {}", why),
+                    Policy::Warn => warning = Some(why),
+                }
+            }
+        }
+        con.write_coil(Coil::ChargeDisconnect, !enabled).await?;
+        Ok(warning)
+    }
+
+    /// Disconnect charging if `s` shows a disconnect alarm or, when
+    /// `disconnect_outside_limits` is set, a battery temperature
+    /// outside the limits. Returns the reason if it disconnected.
+    pub async fn enforce(&self, con: &Connection, s: &Stats) -> Result<Option<String>> {
+        let why = match self.alarmed(s) {
+            Some(why) => Some(why),
+            None if self.disconnect_outside_limits => self.outside_limits(s),
+            None => None,
+        };
+        if why.is_some() {
+            con.write_coil(Coil::ChargeDisconnect, true).await?;
+        }
+        Ok(why)
+    }
+}
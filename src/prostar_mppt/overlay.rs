@@ -0,0 +1,132 @@
+/*!
+Extra registers decoded alongside `Stats`.
+
+OEM firmware, and registers Morningstar documents after a release of
+this crate, can be read without waiting for a new release by
+describing them in an `Overlay`. Every field in the overlay is read
+with the stats and lands in `Stats::extras` under its name, so it
+shows up in `Display` and anything serialized with serde. The compact
+encodings carry only the fixed fields.
+
+An overlay is a JSON list of fields, e.g.
+
+```json
+[
+    {"name": "oem_fan_speed", "address": 100, "codec": "U16", "unit": "rpm"},
+    {"name": "oem_bus_voltage", "address": 102, "codec": "F16", "unit": "V"},
+    {"name": "oem_runtime", "address": 104, "codec": "U32", "scale": 0.1, "unit": "h"}
+]
+```
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, overlay::Overlay};
+# async fn run() {
+
+let overlay = Overlay::load("oem.json").expect("failed to load overlay");
+let con = ps::Connection::new("/dev/ttyUSB0", 1)
+    .await
+    .expect("connection failed")
+    .overlay(overlay);
+let stats = con.stats().await.expect("failed to get stats");
+println!("{:?}", stats.extras.get("oem_fan_speed"));
+# }
+```
+*/
+use super::{gf32, gu32};
+use anyhow::{Context, Result};
+use std::{fmt, fs::File, io::BufReader, path::Path};
+
+/// How a field is stored in its register(s)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    U16,
+    I16,
+    /// two registers, high word first
+    U32,
+    F16,
+}
+
+impl Codec {
+    /// The number of registers a field takes
+    pub fn registers(&self) -> usize {
+        match self {
+            Codec::U16 | Codec::I16 | Codec::F16 => 1,
+            Codec::U32 => 2,
+        }
+    }
+
+    fn decode(&self, raw: &[u16]) -> f64 {
+        match self {
+            Codec::U16 => raw[0] as f64,
+            Codec::I16 => raw[0] as i16 as f64,
+            Codec::U32 => gu32(raw[0], raw[1]) as f64,
+            Codec::F16 => gf32(raw[0]) as f64,
+        }
+    }
+}
+
+fn one() -> f64 {
+    1.
+}
+
+/// A user defined field
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldDef {
+    pub name: String,
+    pub address: u16,
+    pub codec: Codec,
+    /// multiplied into the decoded value, 1 if absent
+    #[serde(default = "one")]
+    pub scale: f64,
+    #[serde(default)]
+    pub unit: Option<String>,
+}
+
+/// A decoded user defined field
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldValue {
+    pub value: f64,
+    pub unit: Option<String>,
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.unit {
+            None => write!(f, "{}", self.value),
+            Some(u) => write!(f, "{} {}", self.value, u),
+        }
+    }
+}
+
+/** A set of user defined fields */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Overlay(pub Vec<FieldDef>);
+
+impl Overlay {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Overlay> {
+        let file = File::open(path).context("failed to open overlay")?;
+        serde_json::from_reader(BufReader::new(file)).context("failed to parse overlay")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Decode `def` from `raw`, the registers starting at its address
+    pub(super) fn decode(def: &FieldDef, raw: &[u16]) -> Result<FieldValue> {
+        if raw.len() < def.codec.registers() {
+            bail!(
+                "{} read {} registers expected {}",
+                def.name,
+                raw.len(),
+                def.codec.registers()
+            )
+        }
+        Ok(FieldValue {
+            value: def.codec.decode(raw) * def.scale,
+            unit: def.unit.clone(),
+        })
+    }
+}
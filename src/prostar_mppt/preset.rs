@@ -0,0 +1,108 @@
+/*!
+Charging presets for common battery chemistries.
+
+The lead acid presets follow the ProStar MPPT battery type DIP switch
+table in Morningstar's manual. The ProStar MPPT has no lithium battery
+type, so the LiFePO4 preset is not a Morningstar profile. Its values
+are generic examples for a 4 cell LiFePO4 battery: 14.2 V absorption
+held for 30 minutes, 13.5 V float, no equalization and no temperature
+compensation. Like the settings themselves, voltages are for a 12 V
+battery and the controller scales them for 24 V and 48 V systems.
+
+These are starting points, not a substitute for the battery maker's
+data sheet. Check the numbers against it, and for lithium take them
+from the battery maker, whose BMS and cells have the final word.
+
+Only the charging fields are changed. Load, LED and other settings
+are left as they were, so apply a preset to settings read from the
+controller.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, preset::BatteryProfile};
+# async fn run() -> anyhow::Result<()> {
+
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await?;
+let mut settings = con.read_settings().await?;
+settings.apply_preset(BatteryProfile::LiFePO4);
+con.write_settings(&settings).await?;
+# Ok(())
+# }
+```
+*/
+use super::Settings;
+use uom::si::{
+    electric_potential::volt,
+    f32::{ElectricPotential, Time},
+    time::{day, minute},
+};
+
+/// A battery type with a charging preset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatteryProfile {
+    /// DIP switch battery type 1, no equalization
+    Gel,
+    /// DIP switch battery type 2, sealed AGM
+    Agm,
+    /// DIP switch battery type 4, flooded lead acid
+    Flooded,
+    /// DIP switch battery type 7, L-16 flooded cells
+    L16,
+    /// lithium iron phosphate, generic example values rather than a
+    /// Morningstar profile, see the module docs
+    LiFePO4,
+}
+
+struct Profile {
+    absorption: f32,
+    float: f32,
+    // minutes
+    absorption_time: f32,
+    // voltage, minutes and days between, None disables equalization
+    equalize: Option<(f32, f32, f32)>,
+    // volts per °C
+    compensation: f32,
+}
+
+impl BatteryProfile {
+    fn profile(&self) -> Profile {
+        let lead_acid = |absorption, float, absorption_time, equalize| Profile {
+            absorption,
+            float,
+            absorption_time,
+            equalize,
+            compensation: 0.03,
+        };
+        match self {
+            BatteryProfile::Gel => lead_acid(14.0, 13.7, 150., None),
+            BatteryProfile::Agm => lead_acid(14.15, 13.7, 150., Some((14.4, 60., 28.))),
+            BatteryProfile::Flooded => {
+                lead_acid(14.4, 13.7, 180., Some((15.1, 120., 28.)))
+            }
+            BatteryProfile::L16 => lead_acid(15.4, 13.4, 180., Some((16.0, 180., 14.))),
+            BatteryProfile::LiFePO4 => Profile {
+                absorption: 14.2,
+                float: 13.5,
+                absorption_time: 30.,
+                equalize: None,
+                compensation: 0.,
+            },
+        }
+    }
+
+    /// Set the charging fields of `settings` for this battery type
+    pub fn apply(&self, settings: &mut Settings) {
+        let p = self.profile();
+        let v = ElectricPotential::new::<volt>;
+        let mn = Time::new::<minute>;
+        settings.regulation_voltage = v(p.absorption);
+        settings.float_voltage = v(p.float);
+        settings.time_before_float = mn(p.absorption_time);
+        settings.temperature_compensation_coefficent = v(p.compensation);
+        let (voltage, time, days) = p.equalize.unwrap_or((p.absorption, 0., 0.));
+        settings.equalize_voltage = v(voltage);
+        settings.equalize_time_limit_above_regulation_voltage = mn(time);
+        settings.equalize_time_limit_at_regulation_voltage = mn(time);
+        settings.days_between_equalize_cycles = Time::new::<day>(days);
+    }
+}
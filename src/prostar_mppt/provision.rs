@@ -0,0 +1,117 @@
+/*!
+Bring a new controller into service.
+
+`provision` performs the whole "new unit out of the box" sequence on a
+controller answering at the factory modbus id: write the settings,
+assign the new modbus id, force an EEPROM update, reset, wait for the
+controller to come back at the new id, and read the settings back to
+verify them. Every step is appended to a log as it happens, so a
+failed run still records how far it got.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, provision};
+# async fn run(settings: ps::Settings) {
+
+let mut log = Vec::new();
+let res = provision::provision("/dev/ttyUSB0", &settings, 12, &mut log).await;
+for step in &log {
+    println!("{}", step);
+}
+let con = res.expect("provisioning failed");
+println!("{}", con.stats().await.expect("failed to get stats"));
+# }
+```
+*/
+use super::{Coil, Connection, ConnectionBuilder, Settings, Timeout};
+use anyhow::Result;
+use chrono::prelude::*;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+/// The modbus id a controller ships with
+pub const DEFAULT_MODBUS_ID: u8 = 1;
+
+/// How long to wait for the controller to answer after a reset
+const RESET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long one operation may take before it is abandoned, long
+/// enough to write every setting one register at a time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One step of a provisioning run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    pub timestamp: DateTime<Local>,
+    pub action: String,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.timestamp, self.action)
+    }
+}
+
+fn step(log: &mut Vec<Step>, action: String) {
+    log.push(Step { timestamp: Local::now(), action })
+}
+
+/// Provision the controller answering at `DEFAULT_MODBUS_ID` on
+/// `device` with `settings`, assigning it `modbus_id` (which overrides
+/// `settings.modbus_id`). On success the returned connection talks to
+/// the controller at its new id. Every request times out after 10 s.
+/// The reset counts as done if the controller doesn't answer it, and
+/// the settings are read back until they verify or 30 s have passed.
+pub async fn provision(
+    device: &str,
+    settings: &Settings,
+    modbus_id: u8,
+    log: &mut Vec<Step>,
+) -> Result<Connection> {
+    let settings = Settings { modbus_id, ..*settings };
+    settings.validate()?;
+    let con = ConnectionBuilder::new(device, DEFAULT_MODBUS_ID)
+        .request_timeout(REQUEST_TIMEOUT)
+        .connect()
+        .await?;
+    con.probe_capabilities().await?;
+    step(log, format!("connected to {} at modbus id {}", device, DEFAULT_MODBUS_ID));
+    con.write_settings(&settings).await?;
+    step(log, format!("wrote settings, modbus id {}", modbus_id));
+    con.write_coil(Coil::ForceEEPROMUpdate, true).await?;
+    step(log, "forced EEPROM update".into());
+    // the controller may reset before it answers
+    match con.write_coil(Coil::ResetControl, true).await {
+        Ok(()) => step(log, "reset".into()),
+        Err(e) if e.is::<Timeout>() => step(log, "reset, no reply".into()),
+        Err(e) => {
+            step(log, format!("reset failed ({:#})", e));
+            return Err(e);
+        }
+    }
+    // same controller at a new id, so keep what the probe found
+    let caps = con.capabilities();
+    con.set_slave(modbus_id);
+    con.set_capabilities(caps);
+    let start = Instant::now();
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        match con.verify_settings(&settings).await {
+            Ok(()) => break,
+            Err(e) => {
+                if start.elapsed() > RESET_TIMEOUT {
+                    step(log, format!("verify failed ({:#})", e));
+                    return Err(e.context(format!(
+                        "controller did not verify at modbus id {}",
+                        modbus_id
+                    )));
+                }
+            }
+        }
+    }
+    step(log, format!("verified settings at modbus id {}", modbus_id));
+    Ok(con)
+}
@@ -0,0 +1,116 @@
+/*!
+A file of known controllers and what has been learned about them.
+
+Each controller is keyed by its serial number, as printed on the
+unit, since the public register map doesn't expose it. The record
+holds where to reach it, descriptive labels, its last seen firmware
+and capabilities, and its current calibration. `Registry::connect`
+opens a controller by serial number and reuses the recorded
+capabilities instead of probing, unless the firmware has changed
+since they were recorded, and attaches the recorded calibration so
+every stats read from the connection is corrected.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::registry::Registry;
+# async fn run() {
+
+let mut reg = Registry::load("devices.json").unwrap_or_default();
+let con = reg.connect("16051234").await.expect("connection failed");
+println!("{}", con.stats().await.expect("failed to get stats"));
+reg.save("devices.json").expect("failed to save registry");
+# }
+```
+*/
+use super::{calibration::Calibration, Capabilities, Connection};
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+/** What is known about one controller */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    /// the serial device it is attached to
+    pub port: String,
+    pub modbus_id: u8,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub site: Option<String>,
+    /// the firmware seen on the last connect
+    #[serde(default)]
+    pub software_version: Option<u16>,
+    /// the capabilities probed with that firmware
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+    #[serde(default)]
+    pub notes: String,
+}
+
+impl Device {
+    pub fn new(port: &str, modbus_id: u8) -> Device {
+        Device {
+            port: port.into(),
+            modbus_id,
+            model: None,
+            site: None,
+            software_version: None,
+            capabilities: None,
+            calibration: None,
+            notes: String::new(),
+        }
+    }
+}
+
+/** Known controllers by serial number */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Registry(pub BTreeMap<String, Device>);
+
+impl Registry {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Registry> {
+        let file = File::open(path).context("failed to open registry")?;
+        serde_json::from_reader(BufReader::new(file)).context("failed to parse registry")
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path).context("failed to create registry")?;
+        serde_json::to_writer_pretty(file, self).context("failed to write registry")
+    }
+
+    /// The serial number and record of the controller at `modbus_id`
+    /// on `port`
+    pub fn find(&self, port: &str, modbus_id: u8) -> Option<(&str, &Device)> {
+        self.0
+            .iter()
+            .find(|(_, d)| d.port == port && d.modbus_id == modbus_id)
+            .map(|(serial, d)| (serial.as_str(), d))
+    }
+
+    /// Connect to the controller with `serial` at 9600 baud 8N2. The
+    /// recorded capabilities are used if its firmware hasn't changed,
+    /// otherwise they are probed again and the record is updated. The
+    /// recorded calibration, if any, is attached to the connection.
+    pub async fn connect(&mut self, serial: &str) -> Result<Connection> {
+        let dev = match self.0.get_mut(serial) {
+            Some(dev) => dev,
+            None => bail!("no controller with serial number {} in the registry", serial),
+        };
+        let mut con = Connection::new(&dev.port, dev.modbus_id).await?;
+        let version = con.software_version().await?;
+        match dev.capabilities {
+            Some(caps) if dev.software_version == Some(version) => {
+                con.set_capabilities(caps)
+            }
+            _ => {
+                dev.capabilities = Some(con.probe_capabilities().await?);
+                dev.software_version = Some(version);
+            }
+        }
+        if let Some(cal) = dev.calibration {
+            con = con.calibration(cal);
+        }
+        Ok(con)
+    }
+}
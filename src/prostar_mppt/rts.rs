@@ -0,0 +1,149 @@
+//! RTS direction control for half duplex RS-485 adapters.
+//!
+//! Adapters without automatic direction control transmit only while
+//! RTS is asserted. `RtsDriven` asserts it before the first byte of
+//! each request, waits `pre_delay`, writes the request, and on flush
+//! waits until the request has had time to leave the port at its baud
+//! rate, waits `post_delay` and releases it so the response can be
+//! received. It doesn't wait for the port to drain, that blocks the
+//! runtime. With no `Rts` configured it passes everything straight
+//! through.
+use super::Rts;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, sleep_until, Instant, Sleep},
+};
+use tokio_serial::{DataBits, Parity, SerialPort, StopBits};
+
+#[derive(Debug)]
+enum State {
+    Receiving,
+    Enabling(Pin<Box<Sleep>>),
+    Sending,
+    Releasing(Pin<Box<Sleep>>),
+}
+
+#[derive(Debug)]
+pub(super) struct RtsDriven<T> {
+    inner: T,
+    rts: Option<Rts>,
+    state: State,
+    // the time to send one character
+    char_time: Duration,
+    // when everything written so far will have left the port
+    drained: Instant,
+}
+
+impl<T: SerialPort> RtsDriven<T> {
+    pub(super) fn new(mut inner: T, rts: Option<Rts>) -> io::Result<RtsDriven<T>> {
+        let mut char_time = Duration::from_secs(0);
+        if rts.is_some() {
+            inner.write_request_to_send(false)?;
+            let data = match inner.data_bits()? {
+                DataBits::Five => 5,
+                DataBits::Six => 6,
+                DataBits::Seven => 7,
+                DataBits::Eight => 8,
+            };
+            let parity = if inner.parity()? == Parity::None { 0 } else { 1 };
+            let stop = if inner.stop_bits()? == StopBits::One { 1 } else { 2 };
+            let bits = 1 + data + parity + stop;
+            char_time = Duration::from_nanos(
+                bits * 1_000_000_000 / inner.baud_rate()?.max(1) as u64,
+            );
+        }
+        let drained = Instant::now();
+        Ok(RtsDriven { inner, rts, state: State::Receiving, char_time, drained })
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RtsDriven<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: SerialPort + AsyncWrite + Unpin> AsyncWrite for RtsDriven<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let rts = match self.rts {
+            None => return Pin::new(&mut self.inner).poll_write(cx, buf),
+            Some(rts) => rts,
+        };
+        loop {
+            match &mut self.state {
+                State::Receiving => {
+                    self.inner.write_request_to_send(true)?;
+                    self.state = State::Enabling(Box::pin(sleep(rts.pre_delay)));
+                }
+                State::Enabling(delay) => {
+                    if delay.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    self.state = State::Sending;
+                }
+                State::Sending => {
+                    let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+                    if let Poll::Ready(Ok(n)) = r {
+                        let start = self.drained.max(Instant::now());
+                        self.drained = start + self.char_time * n as u32;
+                    }
+                    return r;
+                }
+                // a write before the last flush finished
+                State::Releasing(_) => self.state = State::Sending,
+            }
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let rts = match self.rts {
+            None => return Pin::new(&mut self.inner).poll_flush(cx),
+            Some(rts) => rts,
+        };
+        loop {
+            match &mut self.state {
+                State::Receiving => return Poll::Ready(Ok(())),
+                State::Enabling(_) => {
+                    self.inner.write_request_to_send(false)?;
+                    self.state = State::Receiving;
+                }
+                State::Sending => {
+                    let release = self.drained + rts.post_delay;
+                    self.state = State::Releasing(Box::pin(sleep_until(release)))
+                }
+                State::Releasing(delay) => {
+                    if delay.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    self.inner.write_request_to_send(false)?;
+                    self.state = State::Receiving;
+                }
+            }
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,103 @@
+/*!
+Battery voltage trend and projected time until load disconnect.
+
+A `Trend` keeps the battery voltage readings from a sliding window
+and fits a line through them. While the battery is discharging,
+following that line down to the controller's LVD setpoint gives a
+rough estimate of how long the load has left. This is more useful to
+act on than the raw voltage, but it is only a projection of the
+current trend. A cloud passing or a load switching on will move it.
+
+# Examples
+```no_run
+use morningstar::prostar_mppt::{self as ps, trend::Trend};
+use std::time::Duration;
+# async fn run() {
+
+let mut trend = Trend::new(Duration::from_secs(3600));
+let con = ps::Connection::new("/dev/ttyUSB0", 1).await.expect("connection failed");
+loop {
+    let stats = con.stats().await.expect("failed to get stats");
+    trend.update(&stats);
+    if let Some(t) = trend.time_to_lvd(&stats) {
+        println!("LVD in {} minutes", t.as_secs() / 60);
+    }
+}
+# }
+```
+*/
+use super::Stats;
+use chrono::prelude::*;
+use std::{collections::VecDeque, time::Duration};
+use uom::si::electric_potential::volt;
+
+/// Fewest samples that are fit
+const MIN_SAMPLES: usize = 3;
+
+/** Linear fit of battery voltage over a sliding window */
+#[derive(Debug, Clone)]
+pub struct Trend {
+    window: Duration,
+    history: VecDeque<(DateTime<Local>, f32)>,
+}
+
+impl Trend {
+    pub fn new(window: Duration) -> Trend {
+        Trend { window, history: VecDeque::new() }
+    }
+
+    /// Add the battery voltage from `s`, dropping samples older than
+    /// the window.
+    pub fn update(&mut self, s: &Stats) {
+        let now = s.timestamp;
+        self.history.push_back((now, s.battery_voltage_slow.get::<volt>()));
+        while let Some((t, _)) = self.history.front() {
+            match (now - *t).to_std() {
+                Ok(age) if age > self.window => {
+                    self.history.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// The least squares slope of the battery voltage in volts per
+    /// hour, None until enough samples spanning some time have been
+    /// seen.
+    pub fn slope(&self) -> Option<f32> {
+        let n = self.history.len();
+        if n < MIN_SAMPLES {
+            return None;
+        }
+        let t0 = self.history[0].0;
+        let hours = |t: DateTime<Local>| (t - t0).num_milliseconds() as f32 / 3_600_000.;
+        let nf = n as f32;
+        let mean_t = self.history.iter().map(|(t, _)| hours(*t)).sum::<f32>() / nf;
+        let mean_v = self.history.iter().map(|(_, v)| v).sum::<f32>() / nf;
+        let (mut cov, mut var) = (0., 0.);
+        for (t, v) in &self.history {
+            let dt = hours(*t) - mean_t;
+            cov += dt * (v - mean_v);
+            var += dt * dt;
+        }
+        if var == 0. {
+            None
+        } else {
+            Some(cov / var)
+        }
+    }
+
+    /// At the current trend, how long until the battery voltage in `s`
+    /// falls to its LVD setpoint. None if the voltage isn't falling,
+    /// is falling too slowly for the time to be represented, or there
+    /// isn't enough history yet, zero if it's already there.
+    pub fn time_to_lvd(&self, s: &Stats) -> Option<Duration> {
+        let slope = self.slope()?;
+        if slope >= 0. {
+            return None;
+        }
+        let margin = s.battery_voltage_slow.get::<volt>() - s.lvd_setpoint.get::<volt>();
+        let hours = (margin / -slope).max(0.);
+        Duration::try_from_secs_f32(hours * 3600.).ok()
+    }
+}
@@ -0,0 +1,135 @@
+/*!
+Unit-safe thresholds for configuration files.
+
+A bare `13.8` in a config file could be volts or millivolts, and a
+bare `55` could be Celsius or Fahrenheit. `Volts`, `Amps` and
+`Celsius` parse strings that carry their unit, like `"13.8V"`,
+`"10 A"` or `"55°C"`, and convert to the matching uom quantity. For
+compatibility they also accept a plain number, read in the type's
+unit. They serialize back to the string form.
+
+# Examples
+```
+use morningstar::units::{Celsius, Volts};
+use uom::si::{electric_potential::volt, f32::ElectricPotential};
+
+let v: Volts = "13.8V".parse().unwrap();
+assert_eq!(ElectricPotential::from(v).get::<volt>(), 13.8);
+assert!("55C".parse::<Celsius>().is_ok());
+assert!("13.8A".parse::<Volts>().is_err());
+```
+*/
+use anyhow::{Error, Result};
+use serde::{
+    de::{self, Deserializer, Visitor},
+    Deserialize, Serialize, Serializer,
+};
+use std::{fmt, str::FromStr};
+use uom::si::{
+    electric_current::ampere,
+    electric_potential::volt,
+    f32::{ElectricCurrent, ElectricPotential, ThermodynamicTemperature},
+    thermodynamic_temperature::degree_celsius,
+};
+
+macro_rules! unit_type {
+    ($(#[$m:meta])* $name:ident, $quantity:ident, $unit:ident, $suffix:expr, [$($alt:expr),*]) => {
+        $(#[$m])*
+        #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+        pub struct $name(pub f32);
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<Self> {
+                let s = s.trim();
+                let num = [$suffix $(, $alt)*]
+                    .iter()
+                    .find_map(|sfx| {
+                        let n = s.len().checked_sub(sfx.len())?;
+                        if s.is_char_boundary(n) && s[n..].eq_ignore_ascii_case(sfx) {
+                            Some(&s[..n])
+                        } else {
+                            None
+                        }
+                    })
+                    .unwrap_or(s)
+                    .trim();
+                match num.parse::<f32>() {
+                    Ok(v) if v.is_finite() => Ok($name(v)),
+                    _ => bail!("invalid {} value {:?}", stringify!($name), s),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}{}", self.0, $suffix)
+            }
+        }
+
+        impl From<$name> for $quantity {
+            fn from(v: $name) -> $quantity {
+                $quantity::new::<$unit>(v.0)
+            }
+        }
+
+        impl From<$quantity> for $name {
+            fn from(q: $quantity) -> $name {
+                $name(q.get::<$unit>())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                struct V;
+
+                impl<'de> Visitor<'de> for V {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                        write!(f, "a number or a string like \"1.5{}\"", $suffix)
+                    }
+
+                    fn visit_str<E: de::Error>(self, s: &str) -> Result<$name, E> {
+                        s.parse().map_err(E::custom)
+                    }
+
+                    fn visit_f64<E: de::Error>(self, v: f64) -> Result<$name, E> {
+                        Ok($name(v as f32))
+                    }
+
+                    fn visit_i64<E: de::Error>(self, v: i64) -> Result<$name, E> {
+                        Ok($name(v as f32))
+                    }
+
+                    fn visit_u64<E: de::Error>(self, v: u64) -> Result<$name, E> {
+                        Ok($name(v as f32))
+                    }
+                }
+
+                d.deserialize_any(V)
+            }
+        }
+    };
+}
+
+unit_type!(
+    /// A voltage, parsed from strings like "13.8V"
+    Volts, ElectricPotential, volt, "V", []
+);
+unit_type!(
+    /// A current, parsed from strings like "10A"
+    Amps, ElectricCurrent, ampere, "A", []
+);
+unit_type!(
+    /// A temperature in degrees Celsius, parsed from strings like
+    /// "55C" or "55°C"
+    Celsius, ThermodynamicTemperature, degree_celsius, "°C", ["C"]
+);